@@ -0,0 +1,46 @@
+//! Measures the throughput of the binary (logicdata) parser on a synthetic capture.
+//!
+//! The sample count defaults to 10M (a 90 MB capture), larger runs set `LTP_BENCH_SAMPLES`:
+//!
+//! ```text
+//! LTP_BENCH_SAMPLES=100000000 cargo bench --bench logicdata
+//! ```
+use logic_trace_parser::logicdata_parser::LogicDataParser;
+use std::env;
+use std::fs::{self, File};
+use std::io::{BufWriter, Write};
+use std::time::Instant;
+
+fn main() {
+    let samples: u64 = env::var("LTP_BENCH_SAMPLES")
+        .ok()
+        .and_then(|n| n.parse().ok())
+        .unwrap_or(10_000_000);
+    let path = env::temp_dir().join(format!("ltp-bench-{}.bin", std::process::id()));
+
+    let mut output = BufWriter::new(File::create(&path).expect("cannot create the capture"));
+    for index in 0..samples {
+        output.write_all(&(index as i64).to_le_bytes()).unwrap();
+        output.write_all(&[index as u8]).unwrap();
+    }
+    output.flush().unwrap();
+    drop(output);
+
+    let start = Instant::now();
+    let input = File::open(&path).expect("cannot open the capture");
+    let mut parsed = 0u64;
+    for res in LogicDataParser::new(input, 1e6) {
+        res.expect("parse error");
+        parsed += 1;
+    }
+    let elapsed = start.elapsed().as_secs_f64();
+    fs::remove_file(&path).unwrap();
+
+    assert_eq!(parsed, samples);
+    println!(
+        "logicdata: {} samples in {:.3}s, {:.1} Msamples/s",
+        parsed,
+        elapsed,
+        parsed as f64 / elapsed / 1e6
+    );
+}
@@ -0,0 +1,40 @@
+use flate2::read::MultiGzDecoder;
+use std::io::{self, Cursor, ErrorKind, Read};
+use xz2::read::XzDecoder;
+
+const GZIP_MAGIC: &[u8] = &[0x1f, 0x8b];
+const XZ_MAGIC: &[u8] = &[0xfd, b'7', b'z', b'X', b'Z', 0x00];
+const ZSTD_MAGIC: &[u8] = &[0x28, 0xb5, 0x2f, 0xfd];
+
+/// Wraps `input` in a decoder if it starts with the magic number of a gzip, xz or zstd stream.
+///
+/// The input is returned as is otherwise.
+pub fn decompress(mut input: Box<dyn Read>) -> io::Result<Box<dyn Read>> {
+    let mut magic = vec![0; XZ_MAGIC.len()];
+    let mut read = 0;
+    while read < magic.len() {
+        match input.read(&mut magic[read..]) {
+            Ok(0) => break,
+            Ok(n) => read += n,
+            Err(ref e) if e.kind() == ErrorKind::Interrupted => {}
+            Err(e) => return Err(e),
+        }
+    }
+    magic.truncate(read);
+
+    let (gzip, xz, zstd) = (
+        magic.starts_with(GZIP_MAGIC),
+        magic.starts_with(XZ_MAGIC),
+        magic.starts_with(ZSTD_MAGIC),
+    );
+    let input = Cursor::new(magic).chain(input);
+    Ok(if gzip {
+        Box::new(MultiGzDecoder::new(input))
+    } else if xz {
+        Box::new(XzDecoder::new_multi_decoder(input))
+    } else if zstd {
+        Box::new(zstd::Decoder::new(input)?)
+    } else {
+        Box::new(input)
+    })
+}
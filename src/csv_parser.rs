@@ -0,0 +1,158 @@
+use crate::error::Error;
+use crate::sample::{Sample, MAX_CHANNELS};
+use std::io::{BufRead, BufReader, ErrorKind, Read};
+
+/// Parses a Saleae Logic (1.x or 2.x) digital CSV export.
+///
+/// The first line is the header: a time column in seconds followed by one column per channel.
+/// Columns named `Channel N` (or `channel_N`, `DN`) are mapped to the channel `N`, the other
+/// columns are mapped to their position after the time column.
+/// Rows that do not change the state of any channel are skipped.
+pub struct CsvParser<T>
+where
+    T: Read,
+{
+    input: BufReader<T>,
+    line: String,
+    line_no: usize,
+    channels: Option<Vec<usize>>,
+    state: Option<u64>,
+    /// Timestamp of the last record, skipped or not.
+    last_ts: Option<f64>,
+    /// Set once an I/O error is met.
+    done: bool,
+}
+
+impl<T> CsvParser<T>
+where
+    T: Read,
+{
+    pub fn new(input: T) -> Self {
+        Self {
+            input: BufReader::new(input),
+            line: String::new(),
+            line_no: 0,
+            channels: None,
+            state: None,
+            last_ts: None,
+            done: false,
+        }
+    }
+
+    fn parse_header(&self) -> Result<Vec<usize>, Error> {
+        let mut columns = self.line.trim().split(',').map(str::trim);
+        match columns.next() {
+            Some(time) if time.to_lowercase().starts_with("time") => {}
+            _ => return Err(self.error("missing time column")),
+        }
+        columns
+            .enumerate()
+            .map(|(idx, name)| {
+                let channel = channel_number(name).unwrap_or(idx);
+                if channel < usize::from(MAX_CHANNELS) {
+                    Ok(channel)
+                } else {
+                    Err(self.error(format!("channel '{}' out of range", name)))
+                }
+            })
+            .collect()
+    }
+
+    fn parse_record(&self, channels: &[usize]) -> Result<(f64, u64), Error> {
+        let mut values = self.line.trim().split(',').map(str::trim);
+        let ts = values
+            .next()
+            .and_then(|ts| ts.parse::<f64>().ok())
+            .filter(|ts| ts.is_finite())
+            .ok_or_else(|| self.error("invalid timestamp"))?;
+        let mut state = 0;
+        let mut count = 0;
+        for (value, &channel) in values.zip(channels) {
+            match value {
+                "0" => {}
+                "1" => state |= 1 << channel,
+                _ => return Err(self.error(format!("invalid value '{}'", value))),
+            }
+            count += 1;
+        }
+        if count != channels.len() {
+            return Err(self.error("missing values"));
+        }
+        Ok((ts, state))
+    }
+
+    fn error<S: Into<String>>(&self, msg: S) -> Error {
+        Error::BadCsvRecord {
+            line: self.line_no,
+            msg: msg.into(),
+        }
+    }
+}
+
+/// Extracts `N` from a `Channel N`, `channel_N` or `DN` column name.
+fn channel_number(name: &str) -> Option<usize> {
+    let name = name.to_lowercase();
+    let num = name
+        .strip_prefix("channel")
+        .or_else(|| name.strip_prefix('d'))?
+        .trim_start_matches([' ', '_']);
+    num.parse().ok()
+}
+
+impl<T> Iterator for CsvParser<T>
+where
+    T: Read,
+{
+    type Item = Result<Sample, Error>;
+    fn next(&mut self) -> Option<Result<Sample, Error>> {
+        loop {
+            self.line.clear();
+            if self.done {
+                return None;
+            }
+            match self.input.read_line(&mut self.line) {
+                Ok(0) => return None,
+                Ok(_) => self.line_no += 1,
+                // a line that is not UTF-8 is skipped, the input is dropped on any other error.
+                Err(e) => {
+                    self.line_no += 1;
+                    self.done = e.kind() != ErrorKind::InvalidData;
+                    return Some(Err(e.into()));
+                }
+            }
+            if self.line.trim().is_empty() {
+                continue;
+            }
+
+            let channels = match self.channels.take() {
+                Some(channels) => channels,
+                None => match self.parse_header() {
+                    Ok(channels) => {
+                        self.channels = Some(channels);
+                        continue;
+                    }
+                    Err(e) => return Some(Err(e)),
+                },
+            };
+            let res = self.parse_record(&channels);
+            self.channels = Some(channels);
+
+            if let Ok((ts, _)) = res {
+                if let Some(previous) = self.last_ts.replace(ts).filter(|&p| p > ts) {
+                    return Some(Err(Error::NonMonotonicTimestamp {
+                        previous,
+                        current: ts,
+                    }));
+                }
+            }
+            match res {
+                Ok((_, state)) if self.state == Some(state) => {}
+                Ok((ts, state)) => {
+                    self.state = Some(state);
+                    return Some(Ok(Sample::new(state, ts)));
+                }
+                Err(e) => return Some(Err(e)),
+            }
+        }
+    }
+}
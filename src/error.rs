@@ -0,0 +1,113 @@
+use std::fmt;
+use std::io;
+use zip::result::ZipError;
+
+/// Errors reported by the input parsers and the decoders.
+///
+/// Decoders forward the errors of the layer below them and keep going, so a single malformed
+/// record or unexpected event does not stop the decoding of the rest of the capture.
+#[derive(Debug)]
+pub enum Error {
+    /// Error reading the input.
+    Io(io::Error),
+    /// The input ended in the middle of a record.
+    TruncatedRecord { expected: usize, read: usize },
+    /// A VCD signal changed to an undefined (`x`) or high impedance (`z`) value.
+    UndefinedValue { ts: f64, signal: String },
+    /// A VCD variable type that is not supported.
+    UnsupportedVarType(String),
+    /// A VCD variable name that cannot be mapped to a channel.
+    BadVarName(String),
+    /// A signal of the channel map that is not declared in the input.
+    UnknownSignal(String),
+    /// A line of a CSV export that cannot be parsed.
+    BadCsvRecord { line: usize, msg: String },
+    /// A sigrok session file that cannot be read.
+    Sigrok(String),
+    /// A Saleae Logic 2 export that cannot be read.
+    Saleae(String),
+    /// A timestamp went backward.
+    NonMonotonicTimestamp { previous: f64, current: f64 },
+    /// The baudrate could not be detected from the first samples.
+    UndetectableBaudrate,
+    /// The input does not look like any of the supported formats.
+    UnknownInputFormat,
+    /// A decoder option out of its valid range.
+    Config(String),
+    /// A decoder met a sequence of events it does not understand.
+    Protocol { ts: f64, msg: String },
+}
+
+impl Error {
+    pub(crate) fn protocol<S: Into<String>>(ts: f64, msg: S) -> Self {
+        Error::Protocol {
+            ts,
+            msg: msg.into(),
+        }
+    }
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Error::Io(e) => write!(f, "I/O error: {}", e),
+            Error::TruncatedRecord { expected, read } => write!(
+                f,
+                "Truncated record: expected {} bytes, got {}",
+                expected, read
+            ),
+            Error::UndefinedValue { ts, signal } => {
+                write!(f, "{:.6} Undefined value on '{}'", ts, signal)
+            }
+            Error::UnsupportedVarType(ty) => write!(f, "Unsupported VarType: {}", ty),
+            Error::BadVarName(name) => write!(
+                f,
+                "Cannot map variable '{}' to a channel, please provide a channel map with --map",
+                name
+            ),
+            Error::UnknownSignal(path) => write!(f, "Signal '{}' not found in the input", path),
+            Error::BadCsvRecord { line, msg } => write!(f, "Line {}: {}", line, msg),
+            Error::Sigrok(msg) => write!(f, "Invalid sigrok session: {}", msg),
+            Error::Saleae(msg) => write!(f, "Invalid Saleae Logic 2 export: {}", msg),
+            Error::NonMonotonicTimestamp { previous, current } => write!(
+                f,
+                "Timestamp must be monotonic: {} after {}",
+                current, previous
+            ),
+            Error::UnknownInputFormat => write!(
+                f,
+                "Unable to detect the input format, please provide one with --input-format"
+            ),
+            Error::UndetectableBaudrate => write!(
+                f,
+                "Unable to detect the baudrate, please provide one with --baud"
+            ),
+            Error::Config(msg) => write!(f, "Invalid decoder configuration: {}", msg),
+            Error::Protocol { ts, msg } => write!(f, "{:.6} {}", ts, msg),
+        }
+    }
+}
+
+impl std::error::Error for Error {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            Error::Io(e) => Some(e),
+            _ => None,
+        }
+    }
+}
+
+impl From<io::Error> for Error {
+    fn from(e: io::Error) -> Self {
+        Error::Io(e)
+    }
+}
+
+impl From<ZipError> for Error {
+    fn from(e: ZipError) -> Self {
+        match e {
+            ZipError::Io(e) => Error::Io(e),
+            e => Error::Sigrok(e.to_string()),
+        }
+    }
+}
@@ -0,0 +1,28 @@
+//! Decoders for logic analyzer traces.
+//!
+//! Samples are read from a capture by one of the input parsers and wrapped in a
+//! [`SampleIterator`](sample::SampleIterator). Protocol decoders are iterator adapters stacked on
+//! top of it: [`Spi`](spi::Spi) then [`Spif`](spif::Spif) for SPI flashes or
+//! [`SpiTransactions`](spi::SpiTransactions) for any SPI device, [`Serial`](serial::Serial) then
+//! [`Wizfi310`](wizfi310::Wizfi310) for UART links.
+
+#[macro_use]
+extern crate nom;
+
+pub mod compression;
+pub mod csv_parser;
+pub mod error;
+pub mod logic2_parser;
+pub mod logicdata_parser;
+pub mod output;
+pub mod raw_parser;
+pub mod sample;
+pub mod serial;
+pub mod spi;
+pub mod spif;
+pub mod sr_parser;
+pub mod vcd_parser;
+pub mod wizfi310;
+
+pub use crate::error::Error;
+pub use crate::sample::Sample;
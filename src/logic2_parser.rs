@@ -0,0 +1,177 @@
+use crate::compression::decompress;
+use crate::error::Error;
+use crate::sample::{Sample, MAX_CHANNELS};
+use nom::{le_f64, le_i32, le_u32, le_u64};
+use std::fs::{self, File};
+use std::io::{BufReader, Read};
+use std::path::Path;
+
+/// Size of the header of a channel export.
+const HEADER_SIZE: usize = 44;
+
+/// Header of a Saleae Logic 2 digital channel export.
+struct Header {
+    version: i32,
+    kind: i32,
+    initial_state: u32,
+    begin_time: f64,
+    transitions: u64,
+}
+
+named!(
+    parse_header<&[u8], Header>,
+    do_parse!(
+        tag!("<SALEAE>") >>
+        version: le_i32 >>
+        kind: le_i32 >>
+        initial_state: le_u32 >>
+        begin_time: le_f64 >>
+        _end_time: le_f64 >>
+        transitions: le_u64 >>
+        (Header { version, kind, initial_state, begin_time, transitions })
+    )
+);
+
+struct Channel {
+    input: Box<dyn Read>,
+    channel: u8,
+    remaining: u64,
+    /// Time of the next transition.
+    next: Option<f64>,
+}
+impl Channel {
+    fn advance(&mut self) -> Result<(), Error> {
+        self.next = None;
+        if self.remaining == 0 {
+            return Ok(());
+        }
+        let mut buffer = [0; 8];
+        self.input.read_exact(&mut buffer)?;
+        self.remaining -= 1;
+        self.next = Some(f64::from_le_bytes(buffer));
+        Ok(())
+    }
+}
+
+/// Parses Saleae Logic 2 binary exports: one `digital_N.bin` file per channel holding the initial
+/// state of the channel and the time of its transitions.
+///
+/// The transitions of all channels are merged by time.
+#[derive(Default)]
+pub struct Logic2Parser {
+    channels: Vec<Channel>,
+    begin_time: Option<f64>,
+    state: u64,
+    started: bool,
+}
+
+impl Logic2Parser {
+    pub fn new() -> Self {
+        Self::default()
+    }
+    /// Adds the export of `channel`.
+    pub fn channel<R: Read + 'static>(mut self, mut input: R, channel: u8) -> Result<Self, Error> {
+        if channel >= MAX_CHANNELS {
+            return Err(Error::Saleae(format!("invalid channel {}", channel)));
+        }
+        let mut buffer = [0; HEADER_SIZE];
+        input.read_exact(&mut buffer)?;
+        let header = match parse_header(&buffer) {
+            Ok((_, header)) => header,
+            Err(_) => return Err(Error::Saleae("invalid header".to_string())),
+        };
+        if header.version > 1 {
+            return Err(Error::Saleae(format!(
+                "unsupported version {}",
+                header.version
+            )));
+        }
+        if header.kind != 0 {
+            return Err(Error::Saleae(
+                "only digital exports are supported".to_string(),
+            ));
+        }
+
+        if header.initial_state != 0 {
+            self.state |= 1 << channel;
+        }
+        self.begin_time = Some(match self.begin_time {
+            Some(begin_time) => header.begin_time.min(begin_time),
+            None => header.begin_time,
+        });
+        let mut channel = Channel {
+            input: Box::new(input),
+            channel,
+            remaining: header.transitions,
+            next: None,
+        };
+        channel.advance()?;
+        self.channels.push(channel);
+        Ok(self)
+    }
+    /// Opens channel exports, `paths` are either `digital_N.bin` files or directories holding them.
+    pub fn open<P: AsRef<Path>>(paths: &[P]) -> Result<Self, Error> {
+        let mut files = Vec::new();
+        for path in paths {
+            let path = path.as_ref();
+            if path.is_dir() {
+                for entry in fs::read_dir(path)? {
+                    let path = entry?.path();
+                    if let Some(channel) = channel_number(&path) {
+                        files.push((path, channel));
+                    }
+                }
+            } else {
+                let channel = channel_number(path).ok_or_else(|| {
+                    Error::Saleae(format!("no channel number in '{}'", path.display()))
+                })?;
+                files.push((path.to_path_buf(), channel));
+            }
+        }
+        if files.is_empty() {
+            return Err(Error::Saleae("no digital_N.bin file found".to_string()));
+        }
+        files.sort();
+
+        let mut parser = Self::new();
+        for (path, channel) in files {
+            let input = decompress(Box::new(BufReader::new(File::open(path)?)))?;
+            parser = parser.channel(input, channel)?;
+        }
+        Ok(parser)
+    }
+}
+
+/// Extracts `N` from a `digital_N.bin` file name, possibly compressed (e.g. `digital_N.bin.gz`).
+pub fn channel_number(path: &Path) -> Option<u8> {
+    let name = path.file_name()?.to_str()?.strip_prefix("digital_")?;
+    match name.split_once('.') {
+        Some((channel, ext)) if ext.starts_with("bin") => channel.parse().ok(),
+        _ => None,
+    }
+}
+
+impl Iterator for Logic2Parser {
+    type Item = Result<Sample, Error>;
+    fn next(&mut self) -> Option<Result<Sample, Error>> {
+        if !self.started {
+            self.started = true;
+            return Some(Ok(Sample::new(self.state, self.begin_time?)));
+        }
+        let ts = self
+            .channels
+            .iter()
+            .filter_map(|ch| ch.next)
+            .fold(None, |min: Option<f64>, ts| {
+                Some(min.map_or(ts, |min| min.min(ts)))
+            })?;
+        // transitions of several channels at the same time make a single sample.
+        for ch in self.channels.iter_mut().filter(|ch| ch.next == Some(ts)) {
+            self.state ^= 1 << ch.channel;
+            if let Err(e) = ch.advance() {
+                return Some(Err(e));
+            }
+        }
+        Some(Ok(Sample::new(self.state, ts)))
+    }
+}
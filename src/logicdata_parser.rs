@@ -1,34 +1,130 @@
+use crate::error::Error;
 use crate::sample::Sample;
-use clap::ArgMatches;
-use nom::{le_i64, le_u8};
-use std::io::Read;
+use nom::{le_i64, le_u16, le_u8, IResult};
+use std::collections::VecDeque;
+use std::io::{BufRead, BufReader, ErrorKind, Read};
+
+/// Size of the 64-bit sample index starting each record.
+const INDEX_SIZE: usize = 8;
+
+/// Reads the channels state: one byte for up to 8 channels, two bytes for up to 16.
+fn parse_state(input: &[u8], channels: u8) -> IResult<&[u8], u64> {
+    if channels > 8 {
+        map!(input, le_u16, u64::from)
+    } else {
+        map!(input, le_u8, u64::from)
+    }
+}
 
 named_args!(
-    parse_sample(freq: f64)<&[u8], Sample>,
+    parse_sample(freq: f64, channels: u8)<&[u8], Sample>,
     do_parse!(
         ts: le_i64 >>
-        smp: le_u8 >>
+        smp: call!(parse_state, channels) >>
         (Sample::new(smp, (ts as f64)/freq))
     )
 );
+
+/// Size of the read buffer, records are parsed by batches filling it.
+const BUFFER_SIZE: usize = 1 << 20;
+
 pub struct LogicDataParser<T>
 where
     T: Read,
 {
-    input: T,
+    input: BufReader<T>,
     freq: f64,
+    channels: u8,
+    batch: VecDeque<Sample>,
+    /// Set once the end of the input or an I/O error is reached.
+    done: bool,
 }
 
 impl<T> LogicDataParser<T>
 where
     T: Read,
 {
-    pub fn new<'a>(input: T, matches: &ArgMatches<'a>) -> Self {
-        let mut freq = value_t!(matches, "freq", f64).unwrap_or_else(|e| e.exit());
-        if freq == 0. {
-            freq = 1.;
+    /// `freq` is the sample frequency used to convert sample indexes to seconds.
+    pub fn new(input: T, freq: f64) -> Self {
+        let freq = if freq == 0. { 1. } else { freq };
+        Self {
+            input: BufReader::with_capacity(BUFFER_SIZE, input),
+            freq,
+            channels: 8,
+            batch: VecDeque::new(),
+            done: false,
+        }
+    }
+    /// Sets the channel count of the export: 8 (default) or 16.
+    pub fn channels(mut self, channels: u8) -> Self {
+        self.channels = if channels > 8 { 16 } else { 8 };
+        self
+    }
+    pub fn channel_count(&self) -> u8 {
+        self.channels
+    }
+    /// Sample frequency the timestamps are computed with.
+    pub fn freq(&self) -> f64 {
+        self.freq
+    }
+    fn record_size(&self) -> usize {
+        INDEX_SIZE + usize::from(self.channels / 8)
+    }
+
+    /// Parses all the complete records of the read buffer, returns false at the end of the input.
+    fn fill_batch(&mut self) -> Result<bool, Error> {
+        let record_size = self.record_size();
+        let buffer = loop {
+            match self.input.fill_buf() {
+                Ok(buffer) => break buffer,
+                Err(ref e) if e.kind() == ErrorKind::Interrupted => {}
+                Err(e) => return Err(e.into()),
+            }
+        };
+        if buffer.is_empty() {
+            return Ok(false);
+        }
+        if buffer.len() < record_size {
+            // a record split at the end of the buffer is read on its own.
+            let mut record = [0; INDEX_SIZE + 2];
+            let mut read = 0;
+            while read < record_size {
+                match self.input.read(&mut record[read..record_size]) {
+                    Ok(0) => {
+                        return Err(Error::TruncatedRecord {
+                            expected: record_size,
+                            read,
+                        })
+                    }
+                    Ok(n) => read += n,
+                    Err(ref e) if e.kind() == ErrorKind::Interrupted => {}
+                    Err(e) => return Err(e.into()),
+                }
+            }
+            return self.parse(&record[..record_size]).map(|_| true);
+        }
+
+        let records = buffer.len() / record_size;
+        for record in buffer.chunks_exact(record_size) {
+            // a complete record always parses.
+            if let Ok((_, sample)) = parse_sample(record, self.freq, self.channels) {
+                self.batch.push_back(sample);
+            }
+        }
+        self.input.consume(records * record_size);
+        Ok(true)
+    }
+    fn parse(&mut self, record: &[u8]) -> Result<(), Error> {
+        match parse_sample(record, self.freq, self.channels) {
+            Ok((_, sample)) => {
+                self.batch.push_back(sample);
+                Ok(())
+            }
+            Err(_) => Err(Error::TruncatedRecord {
+                expected: self.record_size(),
+                read: record.len(),
+            }),
         }
-        Self { input, freq }
     }
 }
 
@@ -36,17 +132,22 @@ impl<T> Iterator for LogicDataParser<T>
 where
     T: Read,
 {
-    type Item = Result<Sample, String>;
-    fn next(&mut self) -> Option<Result<Sample, String>> {
-        let mut buffer = [0; 9];
-
-        if self.input.read_exact(&mut buffer).is_ok() {
-            match parse_sample(&buffer, self.freq) {
-                Ok((_, sample)) => Some(Ok(sample)),
-                Err(msg) => Some(Err(format!("{:?}", msg))),
+    type Item = Result<Sample, Error>;
+    fn next(&mut self) -> Option<Result<Sample, Error>> {
+        while self.batch.is_empty() {
+            if self.done {
+                return None;
+            }
+            match self.fill_batch() {
+                Ok(true) => {}
+                Ok(false) => self.done = true,
+                Err(e) => {
+                    // a truncated record ends the input as well.
+                    self.done = true;
+                    return Some(Err(e));
+                }
             }
-        } else {
-            None
         }
+        self.batch.pop_front().map(Ok)
     }
 }
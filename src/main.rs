@@ -1,20 +1,27 @@
-#[macro_use]
-extern crate nom;
-
 #[macro_use]
 extern crate clap;
 
-use clap::{App, AppSettings, Arg};
+use clap::{App, AppSettings, Arg, ArgMatches, SubCommand};
+use logic_trace_parser::csv_parser::CsvParser;
+use logic_trace_parser::logic2_parser::{self, Logic2Parser};
+use logic_trace_parser::logicdata_parser::LogicDataParser;
+use logic_trace_parser::output::Format;
+use logic_trace_parser::raw_parser::RawParser;
+use logic_trace_parser::sample::{
+    self, Input, InputFormat, SampleIterator, Sniffed, TimeOrigin, MAX_CHANNELS,
+};
+use logic_trace_parser::serial::{Parity, Serial, SerialBuilder};
+use logic_trace_parser::spi::{Mode, Polarity, Spi, SpiBuilder, SpiTransactions};
+use logic_trace_parser::spif::Spif;
+use logic_trace_parser::sr_parser::SrParser;
+use logic_trace_parser::vcd_parser::{Undefined, VcdParser};
+use logic_trace_parser::wizfi310::Wizfi310;
+use logic_trace_parser::{compression, Error};
 
-mod logicdata_parser;
-mod sample;
-mod serial;
-mod spi;
-mod spif;
-mod vcd_parser;
-mod wizfi310;
+use std::io::{self, stdin, Cursor, Read};
+use std::path::Path;
 
-use std::io::{stdin, Read};
+type Samples = SampleIterator<Sniffed<Box<dyn Read>>>;
 
 fn main() {
     let matches = App::new(crate_name!())
@@ -23,39 +30,491 @@ fn main() {
         .author(crate_authors!())
         .about(crate_description!())
         .arg(Arg::from_usage("--vcd 'Input is a vcd file'").global(true))
-        .subcommand(spi::subcommand())
-        .subcommand(spif::subcommand())
-        .subcommand(serial::subcommand())
-        .subcommand(wizfi310::subcommand())
+        .arg(Arg::from_usage("--csv 'Input is a Saleae Logic csv export'").global(true))
+        .arg(Arg::from_usage("--raw 'Input is a dense stream of samples taken at --freq'").global(true))
+        .arg(
+            Arg::from_usage(
+                "--map [map] 'Maps channels to VCD signals or sigrok probes (e.g. clk=top.spi.sck,cs=top.spi.cs_n)'",
+            )
+            .global(true),
+        )
+        .arg(
+            Arg::from_usage("--undefined [undefined] 'Level of undefined (x) and high impedance (z) VCD values'")
+                .possible_values(&["zero", "last", "warn"])
+                .default_value("warn")
+                .global(true),
+        )
+        .arg(
+            Arg::from_usage("--time-origin [origin] 'Time origin: zero, first (sample) or an offset in seconds'")
+                .default_value("zero")
+                // the offset may be negative.
+                .allow_hyphen_values(true)
+                .global(true),
+        )
+        .arg(
+            Arg::from_usage("--input-format [input_format] 'Input format'")
+                .possible_values(&["auto", "vcd", "csv", "binary", "sr", "logic2", "raw"])
+                .default_value("auto")
+                .global(true),
+        )
+        .arg(
+            Arg::from_usage("--format [format] 'Output format'")
+                .possible_values(&["text", "jsonl", "csv"])
+                .default_value("text")
+                .global(true),
+        )
+        .subcommand(
+            SubCommand::with_name("spi")
+                .args(&spi_args())
+                .arg(Arg::from_usage(
+                    "--transactions 'Reports the data exchanged while the chip select is asserted'",
+                )),
+        )
+        .subcommand(SubCommand::with_name("spif").args(&spi_args()))
+        .subcommand(SubCommand::with_name("serial").args(&serial_args()))
+        .subcommand(SubCommand::with_name("wizfi310").args(&serial_args()))
         .args(&[
-            Arg::from_usage("-f, --freq [freq] 'Sample frequency (only used on binary input)'")
+            Arg::from_usage("-f, --freq [freq] 'Sample frequency (only used on binary and raw input)'")
                 .default_value("1.")
                 .global(true),
+            Arg::from_usage("--channels [channels] 'Channel count (only used on binary and raw input)'")
+                .possible_values(&["8", "16"])
+                .default_value("8")
+                .global(true),
             Arg::with_name("v")
                 .short("v")
                 .multiple(true)
                 .help("Sets the level of verbosity")
                 .global(true),
             Arg::with_name("file")
-                .help("Input file. If not provided, stdin will be used.")
+                .multiple(true)
+                .help(
+                    "Input file. If not provided, stdin will be used. Saleae Logic 2 exports can \
+                     be given as a directory or a set of digital_N.bin files.",
+                )
                 .global(true),
         ])
         .get_matches();
 
-    let input: Box<Read> = if let Some(path) = matches.value_of("file") {
-        Box::new(std::fs::File::open(path).unwrap_or_else(|e| {
-            clap::Error::with_description(&format!("{:?}", e), clap::ErrorKind::ValueValidation)
+    let files: Vec<&str> = matches
+        .values_of("file")
+        .map(Iterator::collect)
+        .unwrap_or_default();
+    let input: Box<dyn Read> = match files[..] {
+        [] => Box::new(stdin()),
+        [path] if !Path::new(path).is_dir() => {
+            Box::new(std::fs::File::open(path).unwrap_or_else(|e| {
+                clap::Error::with_description(&format!("{:?}", e), clap::ErrorKind::ValueValidation)
+                    .exit()
+            }))
+        }
+        // directories and sets of files are opened by the parser.
+        _ => Box::new(io::empty()),
+    };
+    // compressed inputs are decompressed before their format is detected.
+    let input = compression::decompress(input).unwrap_or_else(|e| {
+        clap::Error::with_description(&format!("{:?}", e), clap::ErrorKind::ValueValidation).exit()
+    });
+
+    match matches.subcommand() {
+        ("spif", Some(matches)) => spif(input, matches, 0).for_each(report),
+        ("spi", Some(matches)) if matches.is_present("transactions") => {
+            spi_transactions(input, matches, 0).for_each(report)
+        }
+        ("spi", Some(matches)) => spi(input, matches, 0).for_each(report),
+        ("serial", Some(matches)) => {
+            let serial = serial(input, matches, 0);
+            report_baud(matches, serial.baud());
+            serial.for_each(report)
+        }
+        ("wizfi310", Some(matches)) => {
+            let wizfi310 = wizfi310(input, matches, 0);
+            report_baud(matches, wizfi310.get_ref().baud());
+            wizfi310.for_each(report)
+        }
+        _ => samples(input, &matches, 0).for_each(report),
+    }
+}
+
+/// Tells which baudrate was detected when none was given.
+fn report_baud(matches: &ArgMatches, baud: f64) {
+    if matches.value_of("baud") == Some("auto") {
+        eprintln!("Detected baudrate: {}", baud);
+    }
+}
+
+/// Events are printed by the decoders themselves, only errors are left to report.
+fn report<T>(res: Result<T, Error>) {
+    if let Err(e) = res {
+        eprintln!("{}", e);
+    }
+}
+
+fn exit(e: &Error) -> ! {
+    clap::Error::with_description(&e.to_string(), clap::ErrorKind::ValueValidation).exit()
+}
+
+/// Tells whether the layer at `depth` prints its events.
+///
+/// Every layer down to the `-v` count does, except in the CSV format where only that layer does so
+/// the output has a single set of columns.
+fn inspect(matches: &ArgMatches, depth: u64) -> bool {
+    let verbosity = matches.occurrences_of("v");
+    match value_t!(matches, "format", Format) {
+        Ok(Format::Csv) => verbosity == depth,
+        _ => verbosity >= depth,
+    }
+}
+
+fn format(matches: &ArgMatches) -> Format {
+    value_t!(matches, "format", Format).unwrap_or_else(|e| e.exit())
+}
+
+/// Reads the channel number of the `name` argument, exiting if it does not exist in `channels`.
+fn channel_arg(matches: &ArgMatches, name: &str, channels: u8) -> u8 {
+    let channel = value_t!(matches, name, u8).unwrap_or_else(|e| e.exit());
+    if channel >= channels {
+        clap::Error::with_description(
+            &format!(
+                "Invalid channel {} for --{}: the input only has {} channels",
+                channel, name, channels
+            ),
+            clap::ErrorKind::ValueValidation,
+        )
+        .exit()
+    }
+    channel
+}
+
+/// Parses a `role=path,...` channel map. `role` is either a channel number or the name of a channel
+/// argument of the decoder (e.g. `clk` for `--clk`).
+fn parse_map<'m>(map: &'m str, matches: &ArgMatches) -> Vec<(&'m str, u8)> {
+    map.split(',')
+        .map(|entry| {
+            let (role, path) = entry.split_once('=').unwrap_or_else(|| {
+                clap::Error::with_description(
+                    &format!("Invalid --map entry '{}', expected role=signal", entry),
+                    clap::ErrorKind::ValueValidation,
+                )
                 .exit()
-        }))
+            });
+            let channel = role
+                .parse::<u8>()
+                .ok()
+                .or_else(|| matches.value_of(role).and_then(|ch| ch.parse().ok()));
+            match channel {
+                Some(channel) if channel < MAX_CHANNELS => (path, channel),
+                _ => clap::Error::with_description(
+                    &format!(
+                        "Invalid --map entry '{}': '{}' is not a channel",
+                        entry, role
+                    ),
+                    clap::ErrorKind::ValueValidation,
+                )
+                .exit(),
+            }
+        })
+        .collect()
+}
+
+/// Builds the parser selected by `--input-format`, `--vcd` or `--csv`, or detected from the first
+/// bytes of the input.
+///
+/// Directories and sets of files are read as Saleae Logic 2 channel exports, `reader` is then not
+/// used.
+fn samples(mut reader: Box<dyn Read>, matches: &ArgMatches, depth: u64) -> Samples {
+    let files: Vec<&str> = matches
+        .values_of("file")
+        .map(Iterator::collect)
+        .unwrap_or_default();
+    let input = if files.len() > 1 || files.iter().any(|f| Path::new(f).is_dir()) {
+        Input::Logic2(Logic2Parser::open(&files).unwrap_or_else(|e| exit(&e)))
     } else {
-        Box::new(stdin())
+        let (head, complete) = sample::sniff(&mut reader).unwrap_or_else(|e| exit(&e));
+        let mut channels = value_t!(matches, "channels", u8).unwrap_or_else(|e| e.exit());
+        let format = match matches.value_of("input-format") {
+            Some(format) if format != "auto" => {
+                value_t!(matches, "input-format", InputFormat).unwrap_or_else(|e| e.exit())
+            }
+            _ if matches.is_present("vcd") => InputFormat::Vcd,
+            _ if matches.is_present("csv") => InputFormat::Csv,
+            _ if matches.is_present("raw") => InputFormat::Raw,
+            _ if head.is_empty() => InputFormat::LogicData,
+            _ => {
+                // the binary layout is only guessed when the channel count was not given.
+                let widths = if matches.occurrences_of("channels") != 0 {
+                    vec![channels]
+                } else {
+                    vec![8, 16]
+                };
+                let (format, width) = InputFormat::detect(&head, complete, &widths)
+                    .unwrap_or_else(|| exit(&Error::UnknownInputFormat));
+                channels = width;
+                format
+            }
+        };
+        let reader = Cursor::new(head).chain(reader);
+        match format {
+            InputFormat::Vcd => {
+                let mut parser = VcdParser::new(reader).undefined(
+                    value_t!(matches, "undefined", Undefined).unwrap_or_else(|e| e.exit()),
+                );
+                if let Some(map) = matches.value_of("map") {
+                    for (path, channel) in parse_map(map, matches) {
+                        parser = parser.map_signal(path, channel);
+                    }
+                }
+                Input::Vcd(parser)
+            }
+            InputFormat::Csv => Input::Csv(CsvParser::new(reader)),
+            InputFormat::Raw => {
+                let freq = value_t!(matches, "freq", f64).unwrap_or_else(|e| e.exit());
+                Input::Raw(RawParser::new(reader, freq).channels(channels))
+            }
+            InputFormat::Sigrok => {
+                let mut parser = SrParser::new(reader);
+                if let Some(map) = matches.value_of("map") {
+                    for (name, channel) in parse_map(map, matches) {
+                        parser = parser.map_probe(name, channel);
+                    }
+                }
+                Input::Sigrok(parser)
+            }
+            InputFormat::Logic2 => {
+                let channel = files
+                    .first()
+                    .and_then(|f| logic2_parser::channel_number(Path::new(f)))
+                    .unwrap_or(0);
+                let parser = Logic2Parser::new()
+                    .channel(reader, channel)
+                    .unwrap_or_else(|e| exit(&e));
+                Input::Logic2(parser)
+            }
+            InputFormat::LogicData => {
+                let freq = value_t!(matches, "freq", f64).unwrap_or_else(|e| e.exit());
+                Input::LogicData(LogicDataParser::new(reader, freq).channels(channels))
+            }
+        }
     };
+    SampleIterator::from_input(input, inspect(matches, depth))
+        .format(format(matches))
+        .time_origin(value_t!(matches, "time-origin", TimeOrigin).unwrap_or_else(|e| e.exit()))
+}
 
-    match matches.subcommand() {
-        ("spif", Some(matches)) => spif::Spif::new(input, &matches, 0).for_each(|_| {}),
-        ("spi", Some(matches)) => spi::Spi::new(input, &matches, 0).for_each(|_| {}),
-        ("serial", Some(matches)) => serial::Serial::new(input, &matches, 0).for_each(|_| {}),
-        ("wizfi310", Some(matches)) => wizfi310::Wizfi310::new(input, &matches, 0).for_each(|_| {}),
-        _ => sample::SampleIterator::new(input, &matches, 0).for_each(|_| {}),
+fn spi(input: Box<dyn Read>, matches: &ArgMatches, depth: u64) -> Spi<Samples> {
+    let mut it = samples(input, matches, depth + 1);
+    let channels = it.channel_count();
+    let mut builder = SpiBuilder::new()
+        .cs(channel_arg(matches, "cs", channels))
+        .miso(channel_arg(matches, "miso", channels))
+        .mosi(channel_arg(matches, "mosi", channels))
+        .clk(channel_arg(matches, "clk", channels))
+        .mode(value_t!(matches, "mode", Mode).unwrap_or_else(|e| e.exit()))
+        .cs_active_level(
+            value_t!(matches, "cs_active_level", Polarity).unwrap_or_else(|e| e.exit()),
+        )
+        .bits(value_t!(matches, "bits", u8).unwrap_or_else(|e| e.exit()))
+        .lsb_first(matches.is_present("lsb-first"))
+        .bus_warnings(matches.is_present("bus-warnings"))
+        .inspect(inspect(matches, depth))
+        .format(format(matches));
+
+    if matches.is_present("qspi") || matches.is_present("qpi") {
+        builder = builder
+            .qspi(
+                channel_arg(matches, "io2", channels),
+                channel_arg(matches, "io3", channels),
+            )
+            .qpi(matches.is_present("qpi"));
+    }
+    if matches.is_present("dummy") {
+        builder = builder.dummy_cycles(value_t!(matches, "dummy", u8).unwrap_or_else(|e| e.exit()));
+    }
+    if let Some(width) = matches.value_of("min-pulse") {
+        // sample ticks are converted with the sample rate of the input.
+        let width = match width.strip_suffix('t') {
+            Some(ticks) => {
+                let rate = it
+                    .sample_rate()
+                    .unwrap_or_else(|e| exit(&e))
+                    .unwrap_or_else(|| {
+                        clap::Error::value_validation_auto(
+                            "the argument 'min-pulse' can only be given in sample ticks on binary, \
+                             raw and sigrok inputs"
+                                .to_string(),
+                        )
+                        .exit()
+                    });
+                ticks.parse::<f64>().map(|ticks| ticks / rate)
+            }
+            None => width.parse(),
+        };
+        builder = builder.min_pulse_width(width.unwrap_or_else(|_| {
+            clap::Error::value_validation_auto(
+                "the argument 'min-pulse' isn't a valid value".to_string(),
+            )
+            .exit()
+        }));
+    }
+    if matches.is_present("setup") {
+        builder = builder.setup_time(value_t!(matches, "setup", f64).unwrap_or_else(|e| e.exit()));
+    }
+    if matches.is_present("hold") {
+        builder = builder.hold_time(value_t!(matches, "hold", f64).unwrap_or_else(|e| e.exit()));
     }
+    if matches.is_present("no-cs") {
+        builder =
+            builder.idle_timeout(value_t!(matches, "no-cs", f64).unwrap_or_else(|e| e.exit()));
+    }
+    if matches.is_present("sdio") {
+        builder = builder.sdio(
+            channel_arg(matches, "sdio", channels),
+            value_t!(matches, "turnaround", u32).unwrap_or_else(|e| e.exit()),
+        );
+    }
+    builder.into_spi(it).unwrap_or_else(|e| exit(&e))
+}
+
+fn spi_transactions(
+    input: Box<dyn Read>,
+    matches: &ArgMatches,
+    depth: u64,
+) -> SpiTransactions<Samples> {
+    SpiTransactions::from_spi(spi(input, matches, depth + 1), inspect(matches, depth))
+        .format(format(matches))
+}
+
+fn spif(input: Box<dyn Read>, matches: &ArgMatches, depth: u64) -> Spif<Samples> {
+    // commands, addresses and data are made of bytes.
+    if value_t!(matches, "bits", u8).unwrap_or_else(|e| e.exit()) != 8 {
+        clap::Error::value_validation_auto(
+            "the argument 'bits' must be 8 to decode a SPI flash".to_string(),
+        )
+        .exit()
+    }
+    Spif::from_spi(spi(input, matches, depth + 1), inspect(matches, depth)).format(format(matches))
+}
+
+fn serial(input: Box<dyn Read>, matches: &ArgMatches, depth: u64) -> Serial<Samples> {
+    let mut it = samples(input, matches, depth + 1);
+    let channels = it.channel_count();
+    let mut builder = SerialBuilder::new()
+        .data_bits(value_t!(matches, "data", u32).unwrap_or_else(|e| e.exit()))
+        .parity(value_t!(matches, "parity", Parity).unwrap_or_else(|e| e.exit()))
+        .stop_bits(value_t!(matches, "stop", f64).unwrap_or_else(|e| e.exit()))
+        .invert(matches.is_present("invert"))
+        .inspect(inspect(matches, depth))
+        .format(format(matches));
+
+    if matches.is_present("de") {
+        builder = builder.half_duplex(
+            channel_arg(matches, "io", channels),
+            channel_arg(matches, "de", channels),
+        );
+    } else {
+        builder = builder
+            .tx(channel_arg(matches, "tx", channels))
+            .rx(channel_arg(matches, "rx", channels));
+    }
+    if matches.is_present("rts") {
+        builder = builder.rts(channel_arg(matches, "rts", channels));
+    }
+    if matches.is_present("cts") {
+        builder = builder.cts(channel_arg(matches, "cts", channels));
+    }
+    if matches.is_present("idle") {
+        builder = builder.idle_bits(value_t!(matches, "idle", f64).unwrap_or_else(|e| e.exit()));
+    }
+    match matches.value_of("baud") {
+        Some("auto") | None => {
+            // the samples are inspected along with the events they produce.
+            builder = builder.detect_baud(&mut it).unwrap_or_else(|e| exit(&e));
+        }
+        Some(baud) => match baud.parse::<u32>() {
+            Ok(val) => builder = builder.baud(val as f64),
+            Err(_) => clap::Error::value_validation_auto(
+                "the argument 'baud' isn't a valid value".to_string(),
+            )
+            .exit(),
+        },
+    }
+
+    builder.into_serial(it).unwrap_or_else(|e| exit(&e))
+}
+
+fn wizfi310(input: Box<dyn Read>, matches: &ArgMatches, depth: u64) -> Wizfi310<Serial<Samples>> {
+    Wizfi310::from_serial(serial(input, matches, depth + 1), inspect(matches, depth))
+        .format(format(matches))
+}
+
+fn spi_args() -> [Arg<'static, 'static>; 20] {
+    [
+        Arg::from_usage("--cs [cs] 'Channel used for the chip select.'").default_value("0"),
+        Arg::from_usage("--miso [miso] 'Channel used for miso'").default_value("1"),
+        Arg::from_usage("--mosi [mosi] 'Channel used for mosi'").default_value("2"),
+        Arg::from_usage("--clk [clk] 'Channel used for the clock'").default_value("3"),
+        Arg::from_usage("-l, --cs_active_level [cs_active_level] 'Chip select active level'")
+            .possible_values(&["High", "Low"])
+            .default_value("Low"),
+        Arg::from_usage("-m --mode [mode] 'Spi mode: 0 (CPOL 0, CPHA 0) to 3 (CPOL 1, CPHA 1)'")
+            .possible_values(&["0", "1", "2", "3"])
+            .default_value("0"),
+        Arg::from_usage("--bits [bits] 'Word size, from 4 to 32 bits'")
+            .default_value("8")
+            .validator(|bits| match bits.parse::<u8>() {
+                Ok(4..=32) => Ok(()),
+                _ => Err("the word size must be between 4 and 32 bits".to_string()),
+            }),
+        Arg::from_usage("--lsb-first 'Words are shifted least significant bit first'"),
+        Arg::from_usage("--qspi 'Decodes dual and quad reads, mosi and miso being IO0 and IO1'"),
+        Arg::from_usage("--qpi 'Commands are sent on 4 lanes (4-4-4)'"),
+        Arg::from_usage("--io2 [io2] 'Channel used for IO2'").default_value("4"),
+        Arg::from_usage("--io3 [io3] 'Channel used for IO3'").default_value("5"),
+        Arg::from_usage(
+            "--dummy [dummy] 'Dummy cycles of dual and quad reads, mode bits included'",
+        ),
+        Arg::from_usage(
+            "--no-cs [timeout] 'No chip select: transactions end once the clock stayed idle for \
+             this duration (in seconds)'",
+        ),
+        Arg::from_usage("--sdio [sdio] 'Channel used for the bidirectional data line of 3-wire SPI'"),
+        Arg::from_usage(
+            "--turnaround [turnaround] 'Bits sent by the controller on sdio before the device answers'",
+        )
+        .default_value("8"),
+        Arg::from_usage(
+            "--min-pulse [width] 'Ignores clock pulses shorter than this width, in seconds or in \
+             sample ticks of binary, raw and sigrok inputs with a t suffix (e.g. 3t)'",
+        ),
+        Arg::from_usage("--setup [setup] 'Warns on data changing less than this time before a sampling edge'"),
+        Arg::from_usage("--hold [hold] 'Warns on data changing less than this time after a sampling edge'"),
+        Arg::from_usage(
+            "--bus-warnings 'Warns on chip select asserted with the clock out of its idle level \
+             and on clock activity without chip select'",
+        ),
+    ]
+}
+
+fn serial_args() -> [Arg<'static, 'static>; 12] {
+    [
+        Arg::from_usage("--tx [tx] 'Channel used for the tx pin'").default_value("0"),
+        Arg::from_usage("--rx [rx] 'Channel used for the rx pin'").default_value("1"),
+        Arg::from_usage("--rts [rts] 'Channel used for the rts pin'"),
+        Arg::from_usage("--cts [cts] 'Channel used for the cts pin'"),
+        Arg::from_usage("--io [io] 'Channel used for the half-duplex data line'").requires("de"),
+        Arg::from_usage("--de [de] 'Channel used for the half-duplex driver enable pin'")
+            .requires("io"),
+        Arg::from_usage("--invert 'Serial line levels are inverted (idle low)'"),
+        Arg::from_usage("-b --baud [baudrate] 'Serial line baudrate'").default_value("auto"),
+        Arg::from_usage("-p --parity [parity] 'Serial line parity'")
+            .possible_values(&["even", "odd", "clear", "set", "none"])
+            .default_value("none"),
+        Arg::from_usage("-d --data [data] 'Serial line data bit count'")
+            .possible_values(&["5", "6", "7", "8", "9"])
+            .default_value("8"),
+        Arg::from_usage("-s --stop [stop] 'Serial line stop bit length'")
+            .possible_values(&["1", "1.5", "2"])
+            .default_value("1"),
+        Arg::from_usage("--idle [idle] 'Report an idle line after this many bit times'"),
+    ]
 }
@@ -0,0 +1,174 @@
+use std::fmt;
+use std::str::FromStr;
+
+/// Format used by the decoders to print the events they inspect.
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub enum Format {
+    /// `<timestamp> <event>` using the Debug formatting of the event.
+    Text,
+    /// One JSON object per line.
+    Jsonl,
+    /// Comma separated values with a header line, for a single layer.
+    Csv,
+}
+impl FromStr for Format {
+    type Err = &'static str;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "text" => Ok(Format::Text),
+            "jsonl" => Ok(Format::Jsonl),
+            "csv" => Ok(Format::Csv),
+            _ => Err("no match"),
+        }
+    }
+}
+
+/// Prints the events of a layer on stdout in the selected format.
+#[derive(Debug)]
+pub struct Printer {
+    format: Format,
+    header_printed: bool,
+}
+impl Printer {
+    pub fn new(format: Format) -> Self {
+        Printer {
+            format,
+            header_printed: false,
+        }
+    }
+    pub fn print<E: Event>(&mut self, ts: f64, ev: &E) {
+        match self.format {
+            Format::Text => ev.print_text(ts),
+            Format::Jsonl => println!("{}", Jsonl(ts, ev)),
+            Format::Csv => {
+                if !self.header_printed {
+                    self.header_printed = true;
+                    println!("time,{}", E::CSV_HEADER.join(","));
+                }
+                println!("{}", Csv(ts, ev));
+            }
+        }
+    }
+}
+
+/// Typed value of an event field.
+#[derive(Debug, Clone, PartialEq)]
+pub enum Value {
+    Bool(bool),
+    Int(u64),
+    Float(f64),
+    Str(String),
+    /// Numbers, a JSON array or a space separated CSV cell.
+    List(Vec<f64>),
+}
+impl Value {
+    /// Formats `v` as a `0x` prefixed hexadecimal string of `digits` digits.
+    pub fn hex<T: fmt::LowerHex>(v: T, digits: usize) -> Value {
+        Value::Str(format!("{:#0width$x}", v, width = digits + 2))
+    }
+    /// Formats `data` as a string of hexadecimal bytes.
+    pub fn hex_bytes(data: &[u8]) -> Value {
+        Value::Str(data.iter().map(|b| format!("{:02x}", b)).collect())
+    }
+}
+
+/// An event produced by a decoder layer.
+pub trait Event: fmt::Debug {
+    /// Name of the layer producing the event.
+    const LAYER: &'static str;
+
+    /// Name of the kind of event.
+    fn kind(&self) -> &'static str;
+    /// Typed fields describing the event.
+    fn fields(&self) -> Vec<(&'static str, Value)>;
+
+    /// Columns of the CSV export, after the time column.
+    const CSV_HEADER: &'static [&'static str];
+    /// Values of the CSV columns. Missing trailing columns are left empty.
+    fn csv_record(&self) -> Vec<Value>;
+
+    /// Prints the event in the text format.
+    fn print_text(&self, ts: f64) {
+        println!("{:.6} {:?}", ts, self);
+    }
+}
+
+struct Jsonl<'a, E>(f64, &'a E);
+impl<'a, E: Event> fmt::Display for Jsonl<'a, E> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let Jsonl(ts, ev) = self;
+        write!(
+            f,
+            "{{\"ts\":{},\"layer\":\"{}\",\"event\":\"{}\"",
+            ts,
+            E::LAYER,
+            ev.kind()
+        )?;
+        for (name, value) in ev.fields() {
+            write!(f, ",\"{}\":", name)?;
+            match value {
+                Value::Bool(b) => write!(f, "{}", b)?,
+                Value::Int(i) => write!(f, "{}", i)?,
+                Value::Float(v) if v.is_finite() => write!(f, "{}", v)?,
+                Value::Float(_) => write!(f, "null")?,
+                Value::Str(s) => write_json_str(f, &s)?,
+                Value::List(list) => {
+                    write!(f, "[")?;
+                    for (i, v) in list.iter().enumerate() {
+                        let sep = if i == 0 { "" } else { "," };
+                        if v.is_finite() {
+                            write!(f, "{}{}", sep, v)?;
+                        } else {
+                            write!(f, "{}null", sep)?;
+                        }
+                    }
+                    write!(f, "]")?
+                }
+            }
+        }
+        write!(f, "}}")
+    }
+}
+
+struct Csv<'a, E>(f64, &'a E);
+impl<'a, E: Event> fmt::Display for Csv<'a, E> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let Csv(ts, ev) = self;
+        write!(f, "{}", ts)?;
+        let mut record = ev.csv_record();
+        record.resize(E::CSV_HEADER.len(), Value::Str(String::new()));
+        for value in record {
+            match value {
+                Value::Bool(b) => write!(f, ",{}", b)?,
+                Value::Int(i) => write!(f, ",{}", i)?,
+                Value::Float(v) => write!(f, ",{}", v)?,
+                Value::Str(ref s) if s.contains(&[',', '"', '\r', '\n'][..]) => {
+                    write!(f, ",\"{}\"", s.replace('"', "\"\""))?
+                }
+                Value::Str(s) => write!(f, ",{}", s)?,
+                Value::List(list) => {
+                    let list: Vec<String> = list.iter().map(f64::to_string).collect();
+                    write!(f, ",{}", list.join(" "))?
+                }
+            }
+        }
+        Ok(())
+    }
+}
+
+fn write_json_str(f: &mut fmt::Formatter, s: &str) -> fmt::Result {
+    write!(f, "\"")?;
+    for c in s.chars() {
+        match c {
+            '"' => write!(f, "\\\"")?,
+            '\\' => write!(f, "\\\\")?,
+            '\n' => write!(f, "\\n")?,
+            '\r' => write!(f, "\\r")?,
+            '\t' => write!(f, "\\t")?,
+            c if (c as u32) < 0x20 => write!(f, "\\u{:04x}", c as u32)?,
+            c => write!(f, "{}", c)?,
+        }
+    }
+    write!(f, "\"")
+}
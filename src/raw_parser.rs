@@ -0,0 +1,127 @@
+use crate::error::Error;
+use crate::sample::Sample;
+use std::io::{BufRead, BufReader, ErrorKind, Read};
+
+/// Parses a dense stream of samples taken at a fixed rate: one byte per sample for up to 8
+/// channels, two bytes (little endian) for up to 16.
+///
+/// Only the samples changing the state of a channel are produced.
+pub struct RawParser<T>
+where
+    T: Read,
+{
+    input: BufReader<T>,
+    freq: f64,
+    channels: u8,
+    index: u64,
+    state: Option<u64>,
+    /// Set once an I/O error is met.
+    done: bool,
+}
+
+impl<T> RawParser<T>
+where
+    T: Read,
+{
+    /// `freq` is the sample rate.
+    pub fn new(input: T, freq: f64) -> Self {
+        let freq = if freq == 0. { 1. } else { freq };
+        Self {
+            input: BufReader::new(input),
+            freq,
+            channels: 8,
+            index: 0,
+            state: None,
+            done: false,
+        }
+    }
+    /// Sets the channel count of the stream: 8 (default) or 16.
+    pub fn channels(mut self, channels: u8) -> Self {
+        self.channels = if channels > 8 { 16 } else { 8 };
+        self
+    }
+    pub fn channel_count(&self) -> u8 {
+        self.channels
+    }
+    /// Sample frequency the timestamps are computed with.
+    pub fn freq(&self) -> f64 {
+        self.freq
+    }
+    /// Records the state of the last sample read, returns a sample if it changed.
+    fn update(&mut self, state: u64) -> Option<Result<Sample, Error>> {
+        if self.state == Some(state) {
+            return None;
+        }
+        self.state = Some(state);
+        let ts = (self.index - 1) as f64 / self.freq;
+        Some(Ok(Sample::new(state, ts)))
+    }
+}
+
+impl<T> Iterator for RawParser<T>
+where
+    T: Read,
+{
+    type Item = Result<Sample, Error>;
+    fn next(&mut self) -> Option<Result<Sample, Error>> {
+        let size = usize::from(self.channels / 8);
+        loop {
+            if self.done {
+                return None;
+            }
+            let buffer = match self.input.fill_buf() {
+                Ok([]) => return None,
+                Ok(buffer) => buffer,
+                Err(ref e) if e.kind() == ErrorKind::Interrupted => continue,
+                Err(e) => {
+                    self.done = true;
+                    return Some(Err(e.into()));
+                }
+            };
+            if buffer.len() < size {
+                // a 16-bit sample split across two reads.
+                let lo = buffer[0];
+                self.input.consume(1);
+                let mut hi = [0; 1];
+                match self.input.read_exact(&mut hi) {
+                    Ok(()) => {}
+                    Err(ref e) if e.kind() == ErrorKind::UnexpectedEof => {
+                        return Some(Err(Error::TruncatedRecord {
+                            expected: size,
+                            read: 1,
+                        }))
+                    }
+                    Err(e) => {
+                        self.done = true;
+                        return Some(Err(e.into()));
+                    }
+                }
+                self.index += 1;
+                match self.update(u64::from(u16::from_le_bytes([lo, hi[0]]))) {
+                    Some(res) => return Some(res),
+                    None => continue,
+                }
+            }
+
+            let mut consumed = 0;
+            let mut changed = None;
+            for sample in buffer.chunks_exact(size) {
+                let state = match *sample {
+                    [lo] => u64::from(lo),
+                    [lo, hi] => u64::from(u16::from_le_bytes([lo, hi])),
+                    _ => unreachable!(),
+                };
+                consumed += size;
+                self.index += 1;
+                if self.state != Some(state) {
+                    changed = Some(state);
+                    break;
+                }
+            }
+            self.input.consume(consumed);
+            if let Some(state) = changed {
+                return self.update(state);
+            }
+        }
+    }
+}
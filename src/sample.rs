@@ -1,10 +1,33 @@
+use crate::csv_parser::CsvParser;
+use crate::error::Error;
+use crate::logic2_parser::Logic2Parser;
 use crate::logicdata_parser::LogicDataParser;
+use crate::output::{Event, Format, Printer, Value};
+use crate::raw_parser::RawParser;
+use crate::sr_parser::SrParser;
 use crate::vcd_parser::VcdParser;
-use clap::ArgMatches;
+use std::collections::VecDeque;
 use std::fmt;
+use std::io::{Chain, Cursor, ErrorKind, Read};
+use std::str::FromStr;
+
+/// Largest number of channels a sample can hold.
+pub const MAX_CHANNELS: u8 = 64;
+
+/// Checks that the `channel` given for `name` exists in a sample.
+pub(crate) fn check_channel(name: &str, channel: u8) -> Result<(), Error> {
+    if channel < MAX_CHANNELS {
+        Ok(())
+    } else {
+        Err(Error::Config(format!(
+            "channel {} of {} does not fit in a {} channel sample",
+            channel, name, MAX_CHANNELS
+        )))
+    }
+}
 
 pub struct Sample {
-    sample: u8,
+    sample: u64,
     timestamp: f64,
 }
 impl fmt::Debug for Sample {
@@ -13,10 +36,10 @@ impl fmt::Debug for Sample {
     }
 }
 impl Sample {
-    pub fn new(sample: u8, timestamp: f64) -> Self {
+    pub fn new(sample: u64, timestamp: f64) -> Self {
         Sample { sample, timestamp }
     }
-    pub fn sample(&self) -> u8 {
+    pub fn sample(&self) -> u64 {
         self.sample
     }
     pub fn timestamp(&self) -> f64 {
@@ -24,45 +47,261 @@ impl Sample {
     }
 }
 
-pub enum SampleIterator<T: 'static + std::io::Read> {
-    Vcd(bool, VcdParser<T>),
-    LogicData(bool, LogicDataParser<T>),
+impl Event for Sample {
+    const LAYER: &'static str = "sample";
+    const CSV_HEADER: &'static [&'static str] = &["channels"];
+
+    fn kind(&self) -> &'static str {
+        "Sample"
+    }
+    fn fields(&self) -> Vec<(&'static str, Value)> {
+        vec![("channels", Value::Int(self.sample))]
+    }
+    fn csv_record(&self) -> Vec<Value> {
+        vec![Value::Int(self.sample)]
+    }
+    fn print_text(&self, _ts: f64) {
+        println!("{:?}", self);
+    }
+}
+
+/// Time origin of the sample timestamps.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum TimeOrigin {
+    /// Timestamps of the input are kept as is.
+    Zero,
+    /// The first sample is at 0.
+    First,
+    /// This instant of the input (in seconds) is at 0.
+    At(f64),
+}
+impl FromStr for TimeOrigin {
+    type Err = String;
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "zero" => Ok(TimeOrigin::Zero),
+            "first" => Ok(TimeOrigin::First),
+            _ => s
+                .parse()
+                .map(TimeOrigin::At)
+                .map_err(|_| format!("Invalid time origin: {}", s)),
+        }
+    }
+}
+
+/// Number of bytes read from the input to detect its format.
+const SNIFF_SIZE: usize = 4096;
+
+/// Supported input formats.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum InputFormat {
+    Vcd,
+    Csv,
+    /// Saleae Logic binary export: a 64-bit sample index followed by the channels state.
+    LogicData,
+    /// sigrok session file.
+    Sigrok,
+    /// Saleae Logic 2 binary export of a single channel.
+    Logic2,
+    /// Dense stream of samples taken at `--freq`.
+    Raw,
+}
+impl FromStr for InputFormat {
+    type Err = String;
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "vcd" => Ok(InputFormat::Vcd),
+            "csv" => Ok(InputFormat::Csv),
+            "binary" => Ok(InputFormat::LogicData),
+            "sr" => Ok(InputFormat::Sigrok),
+            "logic2" => Ok(InputFormat::Logic2),
+            "raw" => Ok(InputFormat::Raw),
+            _ => Err(format!("Unknown input format: {}", s)),
+        }
+    }
+}
+impl InputFormat {
+    /// Guesses the format from the first bytes of the input.
+    ///
+    /// `complete` tells if `head` holds the whole input. Binary records are tried for each of the
+    /// `channels` widths, the first one matching is returned along with the format.
+    pub fn detect(head: &[u8], complete: bool, channels: &[u8]) -> Option<(InputFormat, u8)> {
+        if head.starts_with(b"<SALEAE>") {
+            return Some((InputFormat::Logic2, MAX_CHANNELS));
+        }
+        if head.starts_with(b"PK\x03\x04") {
+            return Some((InputFormat::Sigrok, MAX_CHANNELS));
+        }
+        let text = String::from_utf8_lossy(head);
+        let text = text.trim_start();
+        if text.starts_with('$') {
+            const KEYWORDS: &[&str] = &[
+                "$date",
+                "$version",
+                "$timescale",
+                "$comment",
+                "$scope",
+                "$var",
+            ];
+            if KEYWORDS.iter().any(|kw| text.starts_with(kw)) {
+                return Some((InputFormat::Vcd, MAX_CHANNELS));
+            }
+        }
+        let first_line = text.lines().next().unwrap_or("");
+        if first_line.to_lowercase().starts_with("time") && first_line.contains(',') {
+            return Some((InputFormat::Csv, MAX_CHANNELS));
+        }
+        channels
+            .iter()
+            .find(|&&channels| is_logicdata(head, complete, channels))
+            .map(|&channels| (InputFormat::LogicData, channels))
+    }
+}
+
+/// Checks that `head` is made of binary records with non-decreasing sample indexes.
+fn is_logicdata(head: &[u8], complete: bool, channels: u8) -> bool {
+    let record_size = 8 + usize::from(channels / 8);
+    if head.is_empty() || (complete && !head.len().is_multiple_of(record_size)) {
+        return false;
+    }
+    let indexes: Vec<i64> = head
+        .chunks_exact(record_size)
+        .map(|record| {
+            let mut index = [0; 8];
+            index.copy_from_slice(&record[..8]);
+            i64::from_le_bytes(index)
+        })
+        .collect();
+    !indexes.is_empty() && indexes.windows(2).all(|w| w[0] <= w[1])
+}
+
+/// A reader whose first bytes have already been consumed to detect its format.
+pub type Sniffed<T> = Chain<Cursor<Vec<u8>>, T>;
+
+/// Reads up to `SNIFF_SIZE` bytes, returns them and whether the end of the input was reached.
+pub fn sniff<T: Read>(reader: &mut T) -> Result<(Vec<u8>, bool), Error> {
+    let mut head = vec![0; SNIFF_SIZE];
+    let mut read = 0;
+    while read < SNIFF_SIZE {
+        match reader.read(&mut head[read..]) {
+            Ok(0) => break,
+            Ok(n) => read += n,
+            Err(ref e) if e.kind() == ErrorKind::Interrupted => {}
+            Err(e) => return Err(e.into()),
+        }
+    }
+    head.truncate(read);
+    Ok((head, read < SNIFF_SIZE))
+}
+
+/// Parsers producing samples from the supported input formats.
+pub enum Input<T: 'static + std::io::Read> {
+    Vcd(VcdParser<T>),
+    LogicData(LogicDataParser<T>),
+    Csv(CsvParser<T>),
+    Sigrok(SrParser<T>),
+    Logic2(Logic2Parser),
+    Raw(RawParser<T>),
+}
+
+pub struct SampleIterator<T: 'static + std::io::Read> {
+    input: Input<T>,
+    inspect: bool,
+    printer: Printer,
+    origin: TimeOrigin,
+    /// Input timestamp of the time origin, known from the first sample.
+    offset: Option<f64>,
+    /// Samples read ahead, not inspected yet.
+    lookahead: VecDeque<Result<Sample, Error>>,
 }
 impl<T> Iterator for SampleIterator<T>
 where
     T: 'static + std::io::Read,
 {
-    type Item = Sample;
-    fn next(&mut self) -> Option<Sample> {
-        let (inspect, res) = match self {
-            SampleIterator::Vcd(ref inspect, parser) => (inspect, parser.next()),
-            SampleIterator::LogicData(ref inspect, parser) => (inspect, parser.next()),
-        };
-        match res {
-            Some(Ok(smp)) => {
-                if *inspect {
-                    println!("{:?}", smp);
-                }
-                Some(smp)
-            }
-            Some(Err(msg)) => {
-                eprintln!("{}", msg);
-                None
+    type Item = Result<Sample, Error>;
+    fn next(&mut self) -> Option<Result<Sample, Error>> {
+        let res = self.lookahead.pop_front().or_else(|| self.read());
+        if let Some(Ok(ref smp)) = res {
+            if self.inspect {
+                self.printer.print(smp.timestamp(), smp);
             }
-            None => None,
         }
+        res
     }
 }
 impl<T> SampleIterator<T>
 where
     T: 'static + std::io::Read,
 {
-    pub fn new<'a>(reader: T, matches: &ArgMatches<'a>, depth: u64) -> Self {
-        let inspect = matches.occurrences_of("v") >= depth;
-        if matches.is_present("vcd") {
-            SampleIterator::Vcd(inspect, VcdParser::new(reader))
-        } else {
-            SampleIterator::LogicData(inspect, LogicDataParser::new(reader, matches))
+    /// Reads the next sample from the input and moves it to the time origin.
+    fn read(&mut self) -> Option<Result<Sample, Error>> {
+        let res = match self.input {
+            Input::Vcd(ref mut parser) => parser.next(),
+            Input::LogicData(ref mut parser) => parser.next(),
+            Input::Csv(ref mut parser) => parser.next(),
+            Input::Sigrok(ref mut parser) => parser.next(),
+            Input::Logic2(ref mut parser) => parser.next(),
+            Input::Raw(ref mut parser) => parser.next(),
+        };
+        res.map(|res| {
+            res.map(|smp| {
+                let origin = self.origin;
+                let offset = *self.offset.get_or_insert_with(|| match origin {
+                    TimeOrigin::Zero => 0.,
+                    TimeOrigin::First => smp.timestamp(),
+                    TimeOrigin::At(offset) => offset,
+                });
+                Sample::new(smp.sample(), smp.timestamp() - offset)
+            })
+        })
+    }
+    pub fn from_input(input: Input<T>, inspect: bool) -> Self {
+        SampleIterator {
+            input,
+            inspect,
+            printer: Printer::new(Format::Text),
+            origin: TimeOrigin::Zero,
+            offset: None,
+            lookahead: VecDeque::new(),
+        }
+    }
+    pub fn format(mut self, format: Format) -> Self {
+        self.printer = Printer::new(format);
+        self
+    }
+    /// Sets the time origin of the timestamps (default: `TimeOrigin::Zero`).
+    pub fn time_origin(mut self, origin: TimeOrigin) -> Self {
+        self.origin = origin;
+        self
+    }
+    /// Reads up to `count` samples ahead and returns them. They are still returned, and
+    /// inspected, by the iterator.
+    pub fn lookahead(&mut self, count: usize) -> &VecDeque<Result<Sample, Error>> {
+        while self.lookahead.len() < count {
+            match self.read() {
+                Some(res) => self.lookahead.push_back(res),
+                None => break,
+            }
+        }
+        &self.lookahead
+    }
+    /// Sample rate of the input, if it is sampled at a fixed rate.
+    ///
+    /// The metadata of a sigrok session is read to find it.
+    pub fn sample_rate(&mut self) -> Result<Option<f64>, Error> {
+        Ok(match self.input {
+            Input::LogicData(ref parser) => Some(parser.freq()),
+            Input::Raw(ref parser) => Some(parser.freq()),
+            Input::Sigrok(ref mut parser) => Some(parser.samplerate()?),
+            Input::Vcd(_) | Input::Csv(_) | Input::Logic2(_) => None,
+        })
+    }
+    /// Number of channels the input can hold.
+    pub fn channel_count(&self) -> u8 {
+        match self.input {
+            Input::LogicData(ref parser) => parser.channel_count(),
+            Input::Raw(ref parser) => parser.channel_count(),
+            Input::Vcd(_) | Input::Csv(_) | Input::Sigrok(_) | Input::Logic2(_) => MAX_CHANNELS,
         }
     }
 }
@@ -1,35 +1,124 @@
-use crate::sample::{Sample, SampleIterator};
-use clap::{App, Arg, ArgMatches, SubCommand};
+use crate::error::Error;
+use crate::output::{Event, Format, Printer, Value};
+use crate::sample::{self, Sample, SampleIterator};
+use std::collections::VecDeque;
 use std::fmt;
 use std::str::FromStr;
 
+/// Number of samples buffered to run the baudrate detection on.
+const AUTO_BAUD_SAMPLES: usize = 4096;
+/// Baudrates the detected rate is snapped to when close enough.
+const STANDARD_BAUDRATES: [f64; 19] = [
+    300., 600., 1200., 2400., 4800., 9600., 14400., 19200., 28800., 38400., 57600., 115200.,
+    230400., 460800., 921600., 1000000., 1500000., 2000000., 3000000.,
+];
+/// Relative tolerance used to compare pulse widths and snap to a standard rate.
+const AUTO_BAUD_TOLERANCE: f64 = 0.05;
+
 #[derive(Clone, Copy)]
 pub enum SerialEvent {
-    Rx(u8),
-    Tx(u8),
+    Rx(u16),
+    Tx(u16),
     Cts(bool),
     Rts(bool),
     TxError(SerialError),
     RxError(SerialError),
+    /// The line was held low for longer than a frame.
+    RxBreak {
+        duration: f64,
+    },
+    TxBreak {
+        duration: f64,
+    },
+    /// The line stayed idle for the configured number of bit times after a frame.
+    RxIdle,
+    TxIdle,
 }
 impl fmt::Debug for SerialEvent {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
         match *self {
-            SerialEvent::Rx(v) => write!(f, "Rx({:?})", v as char),
-            SerialEvent::Tx(v) => write!(f, "Tx({:?})", v as char),
+            SerialEvent::Rx(v) if v > 0xFF => write!(f, "Rx({:#05x})", v),
+            SerialEvent::Tx(v) if v > 0xFF => write!(f, "Tx({:#05x})", v),
+            SerialEvent::Rx(v) => write!(f, "Rx({:?})", v as u8 as char),
+            SerialEvent::Tx(v) => write!(f, "Tx({:?})", v as u8 as char),
             SerialEvent::Cts(b) => write!(f, "Cts({})", b),
             SerialEvent::Rts(b) => write!(f, "Rts({})", b),
             SerialEvent::RxError(e) => write!(f, "RxError({:?})", e),
             SerialEvent::TxError(e) => write!(f, "TxError({:?})", e),
+            SerialEvent::RxBreak { duration } => write!(f, "RxBreak({:.6})", duration),
+            SerialEvent::TxBreak { duration } => write!(f, "TxBreak({:.6})", duration),
+            SerialEvent::RxIdle => write!(f, "RxIdle"),
+            SerialEvent::TxIdle => write!(f, "TxIdle"),
         }
     }
 }
-impl SerialEvent {
-    pub fn is_error(&self) -> bool {
+impl Event for SerialEvent {
+    const LAYER: &'static str = "serial";
+    const CSV_HEADER: &'static [&'static str] =
+        &["dir", "event", "byte", "error", "level", "duration"];
+
+    fn kind(&self) -> &'static str {
         match self {
-            SerialEvent::RxError(_) | SerialEvent::TxError(_) => true,
-            _ => false,
+            SerialEvent::Rx(_) => "Rx",
+            SerialEvent::Tx(_) => "Tx",
+            SerialEvent::Cts(_) => "Cts",
+            SerialEvent::Rts(_) => "Rts",
+            SerialEvent::RxError(_) => "RxError",
+            SerialEvent::TxError(_) => "TxError",
+            SerialEvent::RxBreak { .. } => "RxBreak",
+            SerialEvent::TxBreak { .. } => "TxBreak",
+            SerialEvent::RxIdle => "RxIdle",
+            SerialEvent::TxIdle => "TxIdle",
+        }
+    }
+    fn fields(&self) -> Vec<(&'static str, Value)> {
+        match *self {
+            SerialEvent::Rx(v) | SerialEvent::Tx(v) => vec![("data", Value::hex(v, 2))],
+            SerialEvent::Cts(b) | SerialEvent::Rts(b) => vec![("level", Value::Bool(b))],
+            SerialEvent::RxError(e) | SerialEvent::TxError(e) => {
+                vec![("error", Value::Str(format!("{:?}", e)))]
+            }
+            SerialEvent::RxBreak { duration } | SerialEvent::TxBreak { duration } => {
+                vec![("duration", Value::Float(duration))]
+            }
+            SerialEvent::RxIdle | SerialEvent::TxIdle => vec![],
+        }
+    }
+    fn csv_record(&self) -> Vec<Value> {
+        // Rts is sampled along with Rx and Cts along with Tx.
+        let (dir, event) = match self {
+            SerialEvent::Rx(_) => ("rx", "Data"),
+            SerialEvent::Tx(_) => ("tx", "Data"),
+            SerialEvent::Rts(_) => ("rx", "Rts"),
+            SerialEvent::Cts(_) => ("tx", "Cts"),
+            SerialEvent::RxError(_) => ("rx", "Error"),
+            SerialEvent::TxError(_) => ("tx", "Error"),
+            SerialEvent::RxBreak { .. } => ("rx", "Break"),
+            SerialEvent::TxBreak { .. } => ("tx", "Break"),
+            SerialEvent::RxIdle => ("rx", "Idle"),
+            SerialEvent::TxIdle => ("tx", "Idle"),
+        };
+        let empty = || Value::Str(String::new());
+        let mut record = vec![Value::Str(dir.to_string()), Value::Str(event.to_string())];
+        match *self {
+            SerialEvent::Rx(v) | SerialEvent::Tx(v) => record.push(Value::hex(v, 2)),
+            SerialEvent::RxError(e) | SerialEvent::TxError(e) => {
+                record.extend(vec![empty(), Value::Str(format!("{:?}", e))])
+            }
+            SerialEvent::Rts(b) | SerialEvent::Cts(b) => {
+                record.extend(vec![empty(), empty(), Value::Bool(b)])
+            }
+            SerialEvent::RxBreak { duration } | SerialEvent::TxBreak { duration } => {
+                record.extend(vec![empty(), empty(), empty(), Value::Float(duration)])
+            }
+            SerialEvent::RxIdle | SerialEvent::TxIdle => {}
         }
+        record
+    }
+}
+impl SerialEvent {
+    pub fn is_error(&self) -> bool {
+        matches!(self, SerialEvent::RxError(_) | SerialEvent::TxError(_))
     }
 }
 #[derive(Debug, Clone, Copy)]
@@ -40,7 +129,7 @@ pub enum SerialError {
     Parity,
 }
 #[derive(Debug, Clone, Copy, PartialEq)]
-enum Parity {
+pub enum Parity {
     Even,
     Odd,
     Set,
@@ -48,7 +137,7 @@ enum Parity {
     None,
 }
 #[derive(Debug, Clone, Copy)]
-enum ParityParseError {
+pub enum ParityParseError {
     InvalidInput,
 }
 impl FromStr for Parity {
@@ -68,46 +157,91 @@ impl FromStr for Parity {
 enum MonitorState {
     Idle,
     Start,
-    Data(u8, u32),
-    Parity(u8),
-    Stop(u8),
+    Data(u16, u32),
+    Parity(u16),
+    Stop(u16),
+    /// Second (or half) stop bit when more than one stop bit is configured.
+    ExtraStop(u16),
+    /// The line is held low past the stop bit.
+    Break,
+}
+impl Parity {
+    /// Returns the expected level of the parity bit for the given data.
+    fn bit(self, data: u16) -> bool {
+        match self {
+            Parity::Even => (data.count_ones() & 1) == 1,
+            Parity::Odd => (data.count_ones() & 1) == 0,
+            Parity::Set => true,
+            Parity::Clear | Parity::None => false,
+        }
+    }
 }
+/// Shape of a serial frame.
+#[derive(Debug, Clone, Copy)]
+struct FrameFormat {
+    bit_duration: f64,
+    /// Number of data bits (5 to 9).
+    data_bits: u32,
+    parity: Parity,
+    /// Number of stop bits (1, 1.5 or 2).
+    stop_bits: f64,
+    /// Number of idle bit times after which an idle event is reported.
+    idle_bits: Option<f64>,
+}
+
 struct Monitor {
-    prefix: &'static str,
     state: MonitorState,
     ts: f64,
     data: bool,
     last_fc: bool,
     bit_duration: f64,
+    data_bits: u32,
     parity: Parity,
-    on_data: &'static Fn(u8) -> SerialEvent,
-    on_err: &'static Fn(SerialError) -> SerialEvent,
-    on_fc: &'static Fn(bool) -> SerialEvent,
+    stop_bits: f64,
+    idle_bits: Option<f64>,
+    /// Start of the current frame.
+    frame_start: f64,
+    /// End of the last frame if the line has not been reported idle since.
+    idle_since: Option<f64>,
+    /// Parity error of an all-zero frame, only reported once the stop bit tells it from a break.
+    pending_parity_error: Option<f64>,
+    on_data: &'static dyn Fn(u16) -> SerialEvent,
+    on_err: &'static dyn Fn(SerialError) -> SerialEvent,
+    on_fc: &'static dyn Fn(bool) -> SerialEvent,
+    on_break: &'static dyn Fn(f64) -> SerialEvent,
+    on_idle: &'static dyn Fn() -> SerialEvent,
 }
 impl Monitor {
     fn new(
-        prefix: &'static str,
-        baud: f64,
-        parity: Parity,
-        on_data: &'static Fn(u8) -> SerialEvent,
-        on_err: &'static Fn(SerialError) -> SerialEvent,
-        on_fc: &'static Fn(bool) -> SerialEvent,
+        format: FrameFormat,
+        on_data: &'static dyn Fn(u16) -> SerialEvent,
+        on_err: &'static dyn Fn(SerialError) -> SerialEvent,
+        on_fc: &'static dyn Fn(bool) -> SerialEvent,
+        on_break: &'static dyn Fn(f64) -> SerialEvent,
+        on_idle: &'static dyn Fn() -> SerialEvent,
     ) -> Self {
         Monitor {
-            prefix,
             state: MonitorState::Idle,
-            ts: -0.1,
+            ts: f64::NEG_INFINITY,
             data: true,
             last_fc: false,
-            bit_duration: 1. / baud,
-            parity,
+            bit_duration: format.bit_duration,
+            data_bits: format.data_bits,
+            parity: format.parity,
+            stop_bits: format.stop_bits,
+            idle_bits: format.idle_bits,
+            frame_start: f64::NEG_INFINITY,
+            idle_since: None,
+            pending_parity_error: None,
             on_data,
             on_err,
             on_fc,
+            on_break,
+            on_idle,
         }
     }
-    fn update(&mut self, ts: f64, data: bool, fc: bool) -> [Option<(f64, SerialEvent)>; 2] {
-        let mut res = [None, None];
+    fn update(&mut self, ts: f64, data: bool, fc: bool) -> [Option<(f64, SerialEvent)>; 4] {
+        let mut res = [None, None, None, None];
         if self.last_fc != fc {
             self.last_fc = fc;
             res[1] = Some((ts, (self.on_fc)(fc)));
@@ -115,21 +249,42 @@ impl Monitor {
 
         while self.ts < ts {
             let (new_ts, new_state) = match self.state {
-                MonitorState::Idle if !data => (ts, MonitorState::Start),
-                MonitorState::Idle => (ts, MonitorState::Idle),
+                MonitorState::Idle => {
+                    if let (Some(since), Some(bits)) = (self.idle_since, self.idle_bits) {
+                        let idle_ts = since + self.bit_duration * bits;
+                        if idle_ts < ts {
+                            res[3] = Some((idle_ts, (self.on_idle)()));
+                            self.idle_since = None;
+                        }
+                    }
+                    if !data {
+                        self.frame_start = ts;
+                        self.idle_since = None;
+                        (ts, MonitorState::Start)
+                    } else {
+                        (ts, MonitorState::Idle)
+                    }
+                }
                 MonitorState::Start if (self.ts + self.bit_duration * 1.5) < ts => (
                     self.ts + self.bit_duration * 1.5,
-                    MonitorState::Data(if self.data { 0x80 } else { 0 }, 1),
+                    MonitorState::Data(
+                        if self.data {
+                            1 << (self.data_bits - 1)
+                        } else {
+                            0
+                        },
+                        1,
+                    ),
                 ),
                 MonitorState::Data(mut reg, mut shift) if (self.ts + self.bit_duration) < ts => {
                     shift += 1;
                     reg >>= 1;
                     if self.data {
-                        reg |= 0x80;
+                        reg |= 1 << (self.data_bits - 1);
                     }
                     (
                         self.ts + self.bit_duration,
-                        if shift == 8 {
+                        if shift == self.data_bits {
                             if self.parity != Parity::None {
                                 MonitorState::Parity(reg)
                             } else {
@@ -140,22 +295,61 @@ impl Monitor {
                         },
                     )
                 }
-                MonitorState::Parity(_) => unimplemented!(),
+                MonitorState::Parity(reg) if (self.ts + self.bit_duration) < ts => {
+                    if self.data != self.parity.bit(reg) {
+                        if reg == 0 && !self.data {
+                            self.pending_parity_error = Some(self.ts);
+                        } else {
+                            res[2] = Some((self.ts, (self.on_err)(SerialError::Parity)));
+                        }
+                    }
+                    (self.ts + self.bit_duration, MonitorState::Stop(reg))
+                }
                 MonitorState::Stop(reg) if (self.ts + self.bit_duration) < ts => {
+                    let mut next = MonitorState::Idle;
+                    let parity_error = self.pending_parity_error.take();
+                    if !self.data && reg == 0 {
+                        next = MonitorState::Break;
+                    } else {
+                        if let Some(parity_ts) = parity_error {
+                            res[2] = Some((parity_ts, (self.on_err)(SerialError::Parity)));
+                        }
+                        if !self.data {
+                            res[0] = Some((self.ts, (self.on_err)(SerialError::Framing)));
+                        } else if self.stop_bits > 1. {
+                            next = MonitorState::ExtraStop(reg);
+                        } else {
+                            res[0] = Some((self.ts, (self.on_data)(reg)));
+                            self.idle_since = Some(self.ts + self.bit_duration);
+                        }
+                    }
+                    (self.ts + self.bit_duration, next)
+                }
+                // sample the middle of the remaining half or full stop bit.
+                MonitorState::ExtraStop(reg)
+                    if (self.ts + self.bit_duration * self.stop_bits / 2.) < ts =>
+                {
+                    let new_ts = self.ts + self.bit_duration * self.stop_bits / 2.;
                     if !self.data {
                         res[0] = Some((self.ts, (self.on_err)(SerialError::Framing)));
                     } else {
                         res[0] = Some((self.ts, (self.on_data)(reg)));
+                        self.idle_since = Some(new_ts);
                     }
-                    (self.ts + self.bit_duration, MonitorState::Idle)
+                    (new_ts, MonitorState::Idle)
+                }
+                MonitorState::Break if data => {
+                    res[0] = Some((ts, (self.on_break)(ts - self.frame_start)));
+                    self.idle_since = Some(ts);
+                    (ts, MonitorState::Idle)
                 }
                 _ => {
                     break;
                 }
             };
             /*println!(
-                "{}: {:.6} {:?} {}-> {:?} ({:.6})",
-                self.prefix, self.ts, self.state, self.data, new_state, new_ts
+                "{:.6} {:?} {}-> {:?} ({:.6})",
+                self.ts, self.state, self.data, new_state, new_ts
             );*/
             self.state = new_state;
             self.ts = new_ts;
@@ -169,69 +363,148 @@ impl Monitor {
             MonitorState::Start | MonitorState::Data(_, _) | MonitorState::Parity(_) => {
                 Some((self.ts, (self.on_err)(SerialError::Framing)))
             }
-            MonitorState::Stop(byte) => Some((self.ts, (self.on_data)(byte))),
+            MonitorState::Stop(byte) | MonitorState::ExtraStop(byte) => {
+                Some((self.ts, (self.on_data)(byte)))
+            }
+            MonitorState::Break => Some((self.ts, (self.on_break)(self.ts - self.frame_start))),
         };
         self.state = MonitorState::Idle;
+        self.pending_parity_error = None;
         res
     }
 }
 
+/// Estimates the baudrate from the shortest stable pulse seen on any of the `masks` channels.
+///
+/// A pulse width is considered stable when it is seen at least 3 times (or as many times as
+/// there are pulses on short captures). The result is snapped to the closest standard baudrate
+/// if the nearest one is within `AUTO_BAUD_TOLERANCE`.
+fn detect_baudrate(samples: &VecDeque<Result<Sample, Error>>, masks: &[u64]) -> Option<f64> {
+    let mut widths = Vec::new();
+    for &mask in masks.iter().filter(|&&m| m != 0) {
+        let mut last: Option<(bool, f64)> = None;
+        for smp in samples.iter().flatten() {
+            let level = (smp.sample() & mask) == mask;
+            match last {
+                Some((lvl, _)) if lvl == level => {}
+                Some((_, ts)) => {
+                    widths.push(smp.timestamp() - ts);
+                    last = Some((level, smp.timestamp()));
+                }
+                // the first edge is not known, the first pulse cannot be measured.
+                None => last = Some((level, f64::NAN)),
+            }
+        }
+    }
+    widths.retain(|w| w.is_finite() && *w > 0.);
+    widths.sort_unstable_by(|a, b| a.total_cmp(b));
+
+    let min_count = widths.len().min(3);
+    let bit_duration = widths.iter().enumerate().find_map(|(i, &w)| {
+        let count = widths[i..]
+            .iter()
+            .take_while(|&&o| o <= w * (1. + AUTO_BAUD_TOLERANCE))
+            .count();
+        if count >= min_count {
+            Some(widths[i..i + count].iter().sum::<f64>() / count as f64)
+        } else {
+            None
+        }
+    })?;
+
+    let measured = 1. / bit_duration;
+    let error = |std: f64| ((measured - std) / std).abs();
+    Some(
+        STANDARD_BAUDRATES
+            .iter()
+            .cloned()
+            .min_by(|&a, &b| error(a).total_cmp(&error(b)))
+            .filter(|&std| error(std) <= AUTO_BAUD_TOLERANCE)
+            .unwrap_or_else(|| measured.round()),
+    )
+}
+
 pub struct Serial<T>
 where
-    T: Iterator<Item = Sample>,
+    T: Iterator<Item = Result<Sample, Error>>,
 {
     it: T,
+    /// Samples consumed by the baudrate detection, replayed before reading from `it`.
+    replay: VecDeque<Result<Sample, Error>>,
     pending_event: Vec<(f64, SerialEvent)>,
     inspect: bool,
+    printer: Printer,
+    /// Baudrate, either configured or detected.
+    baud: f64,
+    /// Line levels are inverted (idle low), as seen before an RS-232 transceiver.
+    invert: bool,
+    /// Driver enable channel in half-duplex mode, 0 in full-duplex mode.
+    /// Bytes sent while DE is asserted are reported as Tx, the others as Rx.
+    de_mask: u64,
 
     // Monitor Rx + RTS
-    rx_mask: u8,
-    rts_mask: u8,
+    rx_mask: u64,
+    rts_mask: u64,
     rx: Monitor,
     // Monitor Tx + CTS
-    tx_mask: u8,
-    cts_mask: u8,
+    tx_mask: u64,
+    cts_mask: u64,
     tx: Monitor,
 }
 
+impl<T> Serial<T>
+where
+    T: Iterator<Item = Result<Sample, Error>>,
+{
+    /// Baudrate the line is decoded at.
+    pub fn baud(&self) -> f64 {
+        self.baud
+    }
+}
+
 impl<T> Iterator for Serial<T>
 where
-    T: Iterator<Item = Sample>,
+    T: Iterator<Item = Result<Sample, Error>>,
 {
-    type Item = (f64, SerialEvent);
+    type Item = Result<(f64, SerialEvent), Error>;
     fn next(&mut self) -> Option<Self::Item> {
-        let ret = if self.pending_event.len() > 0 {
+        let ret = if !self.pending_event.is_empty() {
             self.pending_event.pop()
         } else {
-            while let Some(smp) = self.it.next() {
+            while let Some(smp) = self.replay.pop_front().or_else(|| self.it.next()) {
+                let smp = match smp {
+                    Ok(smp) => smp,
+                    Err(e) => return Some(Err(e)),
+                };
                 let ts = smp.timestamp();
                 let smp = smp.sample();
 
+                let mut rx = ((smp & self.rx_mask) == self.rx_mask) != self.invert;
+                let mut tx = ((smp & self.tx_mask) == self.tx_mask) != self.invert;
+                if self.de_mask != 0 {
+                    // the monitor not driving the line sees it idle.
+                    let de = (smp & self.de_mask) == self.de_mask;
+                    rx |= de;
+                    tx |= !de;
+                }
+
                 self.pending_event.extend(
                     self.rx
-                        .update(
-                            ts,
-                            (smp & self.rx_mask) == self.rx_mask,
-                            (smp & self.rts_mask) == self.rts_mask,
-                        )
+                        .update(ts, rx, (smp & self.rts_mask) == self.rts_mask)
                         .iter()
                         .flatten(),
                 );
                 self.pending_event.extend(
                     self.tx
-                        .update(
-                            ts,
-                            (smp & self.tx_mask) == self.tx_mask,
-                            (smp & self.cts_mask) == self.cts_mask,
-                        )
+                        .update(ts, tx, (smp & self.cts_mask) == self.cts_mask)
                         .iter()
                         .flatten(),
                 );
-                if self.pending_event.len() > 0 {
+                if !self.pending_event.is_empty() {
                     break;
                 }
             }
-            if self.pending_event.len() == 0 {
+            if self.pending_event.is_empty() {
                 if let Some(tx) = self.tx.finalize() {
                     self.pending_event.push(tx);
                 }
@@ -240,183 +513,208 @@ where
                 }
             }
             self.pending_event
-                .sort_unstable_by(|a, b| b.0.partial_cmp(&a.0).unwrap());
+                .sort_unstable_by(|a, b| b.0.total_cmp(&a.0));
             self.pending_event.pop()
         };
         if self.inspect {
-            if let Some((ref ts, ref ev)) = ret {
-                println!("{:.6} {:?}", ts, ev);
+            if let Some((ts, ref ev)) = ret {
+                self.printer.print(ts, ev);
             }
         }
-        ret
+        ret.map(Ok)
     }
 }
 
-impl<T> Serial<SampleIterator<T>>
-where
-    T: 'static + std::io::Read,
-{
-    pub fn new<'a>(input: T, matches: &ArgMatches<'a>, depth: u64) -> Serial<SampleIterator<T>> {
-        let inspect = matches.occurrences_of("v") >= depth;
-
-        let tx_mask = 1 << value_t!(matches, "tx", u8).unwrap_or_else(|e| e.exit());
-        let rx_mask = 1 << value_t!(matches, "rx", u8).unwrap_or_else(|e| e.exit());
-        let rts_mask = if let Some(v) = matches.value_of("rts") {
-            match v.parse::<u8>() {
-                Ok(val) => 1 << val,
-                Err(_) => ::clap::Error::value_validation_auto(
-                    "the argument 'rts' isn't a valid value".to_string(),
-                )
-                .exit(),
-            }
-        } else {
-            0
+#[derive(Debug)]
+pub struct SerialBuilder {
+    tx: u8,
+    rx: u8,
+    rts: Option<u8>,
+    cts: Option<u8>,
+    half_duplex: Option<(u8, u8)>,
+    invert: bool,
+    baud: Option<f64>,
+    data_bits: u32,
+    parity: Parity,
+    stop_bits: f64,
+    idle_bits: Option<f64>,
+    inspect: bool,
+    format: Format,
+}
+impl Default for SerialBuilder {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+impl SerialBuilder {
+    pub fn new() -> Self {
+        Self {
+            tx: 0,
+            rx: 1,
+            rts: None,
+            cts: None,
+            half_duplex: None,
+            invert: false,
+            baud: None,
+            data_bits: 8,
+            parity: Parity::None,
+            stop_bits: 1.,
+            idle_bits: None,
+            inspect: false,
+            format: Format::Text,
+        }
+    }
+    pub fn tx(mut self, tx: u8) -> Self {
+        self.tx = tx;
+        self
+    }
+    pub fn rx(mut self, rx: u8) -> Self {
+        self.rx = rx;
+        self
+    }
+    pub fn rts(mut self, rts: u8) -> Self {
+        self.rts = Some(rts);
+        self
+    }
+    pub fn cts(mut self, cts: u8) -> Self {
+        self.cts = Some(cts);
+        self
+    }
+    /// Decodes a single data line `io`, attributing bytes to Tx while `de` is high and to Rx
+    /// otherwise. `tx` and `rx` are ignored in this mode.
+    pub fn half_duplex(mut self, io: u8, de: u8) -> Self {
+        self.half_duplex = Some((io, de));
+        self
+    }
+    pub fn invert(mut self, invert: bool) -> Self {
+        self.invert = invert;
+        self
+    }
+    /// Sets the baudrate. If not set, it is detected from the first samples.
+    pub fn baud(mut self, baud: f64) -> Self {
+        self.baud = Some(baud);
+        self
+    }
+    pub fn data_bits(mut self, data_bits: u32) -> Self {
+        self.data_bits = data_bits;
+        self
+    }
+    pub fn parity(mut self, parity: Parity) -> Self {
+        self.parity = parity;
+        self
+    }
+    pub fn stop_bits(mut self, stop_bits: f64) -> Self {
+        self.stop_bits = stop_bits;
+        self
+    }
+    pub fn idle_bits(mut self, idle_bits: f64) -> Self {
+        self.idle_bits = Some(idle_bits);
+        self
+    }
+    pub fn inspect(mut self, inspect: bool) -> Self {
+        self.inspect = inspect;
+        self
+    }
+    pub fn format(mut self, format: Format) -> Self {
+        self.format = format;
+        self
+    }
+    /// Masks of the tx, rx and driver enable channels.
+    fn masks(&self) -> (u64, u64, u64) {
+        match self.half_duplex {
+            Some((io, de)) => (1 << io, 1 << io, 1 << de),
+            None => (1 << self.tx, 1 << self.rx, 0),
+        }
+    }
+    /// Sets the baudrate detected from the first samples of `it`. The samples are read ahead, they
+    /// are still returned and inspected in order when the decoder reads them.
+    pub fn detect_baud<R: std::io::Read>(self, it: &mut SampleIterator<R>) -> Result<Self, Error> {
+        self.check()?;
+        let (tx_mask, rx_mask, _) = self.masks();
+        let samples = it.lookahead(AUTO_BAUD_SAMPLES);
+        let baud =
+            detect_baudrate(samples, &[rx_mask, tx_mask]).ok_or(Error::UndetectableBaudrate)?;
+        Ok(self.baud(baud))
+    }
+    /// Checks that the channels fit in a sample and that the frame format is in range.
+    fn check(&self) -> Result<(), Error> {
+        let mut channels = match self.half_duplex {
+            Some((io, de)) => vec![("io", io), ("de", de)],
+            None => vec![("tx", self.tx), ("rx", self.rx)],
         };
-        let cts_mask = if let Some(v) = matches.value_of("cts") {
-            match v.parse::<u8>() {
-                Ok(val) => 1 << val,
-                Err(_) => ::clap::Error::value_validation_auto(
-                    "the argument 'cts' isn't a valid value".to_string(),
-                )
-                .exit(),
+        channels.extend(self.rts.map(|rts| ("rts", rts)));
+        channels.extend(self.cts.map(|cts| ("cts", cts)));
+        for (name, channel) in channels {
+            sample::check_channel(name, channel)?;
+        }
+        if !(5..=9).contains(&self.data_bits) {
+            return Err(Error::Config(format!(
+                "{} data bits, must be between 5 and 9",
+                self.data_bits
+            )));
+        }
+        match self.baud {
+            Some(baud) if !(baud.is_finite() && baud > 0.) => Err(Error::Config(format!(
+                "baudrate of {}, must be positive",
+                baud
+            ))),
+            _ => Ok(()),
+        }
+    }
+    /// Builds the decoder. This fails if a channel does not fit in a sample, if the frame format is
+    /// out of range or if the baudrate needs to be detected and could not be.
+    pub fn into_serial<T: Iterator<Item = Result<Sample, Error>>>(
+        self,
+        mut it: T,
+    ) -> Result<Serial<T>, Error> {
+        self.check()?;
+        let (tx_mask, rx_mask, de_mask) = self.masks();
+
+        let mut replay = VecDeque::new();
+        let baud = match self.baud {
+            Some(baud) => baud,
+            None => {
+                replay.extend(it.by_ref().take(AUTO_BAUD_SAMPLES));
+                detect_baudrate(&replay, &[rx_mask, tx_mask]).ok_or(Error::UndetectableBaudrate)?
             }
-        } else {
-            0
         };
-        let baud = if let Some(baud) = matches.value_of("baud") {
-            if baud == "auto" {
-                ::clap::Error::with_description(
-                    "Auto baudrate detection not yet implemented",
-                    ::clap::ErrorKind::ValueValidation,
-                )
-                .exit();
-            } else {
-                match baud.parse::<u32>() {
-                    Ok(val) => val as f64,
-                    Err(_) => ::clap::Error::value_validation_auto(
-                        "the argument 'baud' isn't a valid value".to_string(),
-                    )
-                    .exit(),
-                }
-            }
-        } else {
-            unreachable!();
+        let format = FrameFormat {
+            bit_duration: 1. / baud,
+            data_bits: self.data_bits,
+            parity: self.parity,
+            stop_bits: self.stop_bits,
+            idle_bits: self.idle_bits,
         };
-        let parity = value_t!(matches, "parity", Parity).unwrap_or_else(|e| e.exit());
 
-        Serial {
-            it: SampleIterator::new(input, matches, depth + 1),
+        Ok(Serial {
+            it,
+            replay,
             pending_event: Vec::with_capacity(4),
-            inspect,
+            inspect: self.inspect,
+            printer: Printer::new(self.format),
+            baud,
+            invert: self.invert,
+            de_mask,
             rx_mask,
-            rts_mask,
+            rts_mask: self.rts.map_or(0, |rts| 1 << rts),
             rx: Monitor::new(
-                "rx",
-                baud,
-                parity,
+                format,
                 &SerialEvent::Rx,
                 &SerialEvent::RxError,
                 &SerialEvent::Rts,
+                &|duration| SerialEvent::RxBreak { duration },
+                &|| SerialEvent::RxIdle,
             ),
             tx_mask,
-            cts_mask,
+            cts_mask: self.cts.map_or(0, |cts| 1 << cts),
             tx: Monitor::new(
-                "tx",
-                baud,
-                parity,
+                format,
                 &SerialEvent::Tx,
                 &SerialEvent::TxError,
                 &SerialEvent::Cts,
+                &|duration| SerialEvent::TxBreak { duration },
+                &|| SerialEvent::TxIdle,
             ),
-        }
+        })
     }
-    /*
-    pub fn new_with_options<'a>(
-        input: T,
-        matches: &ArgMatches<'a>,
-        depth: u64,
-        rx: Option<u8>,
-        rts: Option<u8>,
-        rx: Option<u8>,
-        cts: Option<u8>,
-        baud: Option<f64>,
-        parity: Option<Parity>,
-    ) -> Serial<SampleIterator<T>> {
-        let inspect = matches.occurrences_of("v") >= depth;
-
-        let tx_mask = 1 << tx.unwrap_or_else(|_| value_t!(matches, "tx", u8).unwrap_or_else(|e| e.exit()));
-        let rx_mask = 1 << rx.unwrap_or_else(|_| value_t!(matches, "rx", u8).unwrap_or_else(|e| e.exit()));
-        let rts_mask = if let Some(v) = matches.value_of("rts") {
-            match v.parse::<u8>() {
-                Ok(val) => 1 << val,
-                Err(_) => ::clap::Error::value_validation_auto(
-                    "the argument 'rts' isn't a valid value".to_string(),
-                )
-                .exit(),
-            }
-        } else {
-            0
-        };
-        let cts_mask = if let Some(v) = matches.value_of("cts") {
-            match v.parse::<u8>() {
-                Ok(val) => 1 << val,
-                Err(_) => ::clap::Error::value_validation_auto(
-                    "the argument 'cts' isn't a valid value".to_string(),
-                )
-                .exit(),
-            }
-        } else {
-            0
-        };
-        let baud = if let Some(baud) = matches.value_of("baud") {
-            if baud == "auto" {
-                ::clap::Error::with_description(
-                    "Auto baudrate detection not yet implemented",
-                    ::clap::ErrorKind::ValueValidation,
-                )
-                .exit();
-            } else {
-                match baud.parse::<u32>() {
-                    Ok(val) => val as f64,
-                    Err(_) => ::clap::Error::value_validation_auto(
-                        "the argument 'baud' isn't a valid value".to_string(),
-                    )
-                    .exit(),
-                }
-            }
-        } else {
-            unreachable!();
-        };
-        let parity = value_t!(matches, "parity", Parity).unwrap_or_else(|e| e.exit());
-
-        Serial {
-            it: SampleIterator::new(input, matches, depth + 1),
-            pending_event: VecDeque::new(),
-            inspect,
-            rx_mask,
-            rts_mask,
-            rx: Monitor::new("rx", baud, parity, &SerialEvent::Rx, &SerialEvent::Rts),
-            tx_mask,
-            cts_mask,
-            tx: Monitor::new("tx", baud, parity, &SerialEvent::Tx, &SerialEvent::Cts),
-        }
-    }*/
-}
-
-pub fn args() -> [Arg<'static, 'static>; 7] {
-    [
-        Arg::from_usage("--tx [tx] 'Channel used for the tx pin'").default_value("0"),
-        Arg::from_usage("--rx [rx] 'Channel used for the rx pin'").default_value("1"),
-        Arg::from_usage("--rts [rts] 'Channel used for the rts pin'"),
-        Arg::from_usage("--cts [cts] 'Channel used for the cts pin'"),
-        Arg::from_usage("-b --baud [baudrate] 'Serial line baudrate'").default_value("auto"),
-        Arg::from_usage("-p --parity [parity] 'Serial line parity'")
-            .possible_values(&["even", "odd", "clear", "set", "none"])
-            .default_value("none"),
-        Arg::from_usage("-s --stop [stop] 'Serial line stop bit length'").default_value("1"),
-    ]
-}
-
-pub fn subcommand() -> App<'static, 'static> {
-    SubCommand::with_name("serial").args(&args())
 }
@@ -1,5 +1,7 @@
-use crate::sample::{Sample, SampleIterator};
-use clap::{App, Arg, ArgMatches, SubCommand};
+use crate::error::Error;
+use crate::output::{Event, Format, Printer, Value};
+use crate::sample::{self, Sample};
+use std::collections::VecDeque;
 use std::fmt;
 use std::str::FromStr;
 
@@ -20,16 +22,248 @@ impl FromStr for Polarity {
     }
 }
 
+/// SPI mode, defined by the clock polarity (CPOL) and phase (CPHA):
+///
+/// | Mode | CPOL | CPHA | Clock idle | Sampling edge |
+/// |------|------|------|------------|---------------|
+/// | 0    | 0    | 0    | low        | rising        |
+/// | 1    | 0    | 1    | low        | falling       |
+/// | 2    | 1    | 0    | high       | falling       |
+/// | 3    | 1    | 1    | high       | rising        |
 #[derive(Debug, Copy, Clone, PartialEq)]
-pub enum Phase {
-    FirstEdge,
-    SecondEdge,
+pub enum Mode {
+    Mode0,
+    Mode1,
+    Mode2,
+    Mode3,
+}
+impl Mode {
+    pub fn from_cpol_cpha(cpol: bool, cpha: bool) -> Self {
+        match (cpol, cpha) {
+            (false, false) => Mode::Mode0,
+            (false, true) => Mode::Mode1,
+            (true, false) => Mode::Mode2,
+            (true, true) => Mode::Mode3,
+        }
+    }
+    /// Clock polarity: the clock idles high.
+    pub fn cpol(self) -> bool {
+        self == Mode::Mode2 || self == Mode::Mode3
+    }
+    /// Clock phase: data is sampled on the second edge of the clock.
+    pub fn cpha(self) -> bool {
+        self == Mode::Mode1 || self == Mode::Mode3
+    }
+    /// Level of the clock after its sampling edge: data is sampled on the rising edge if true.
+    fn sampling_level(self) -> bool {
+        self.cpol() == self.cpha()
+    }
+}
+impl FromStr for Mode {
+    type Err = &'static str;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "0" => Ok(Mode::Mode0),
+            "1" => Ok(Mode::Mode1),
+            "2" => Ok(Mode::Mode2),
+            "3" => Ok(Mode::Mode3),
+            _ => Err("no match"),
+        }
+    }
+}
+
+/// Default word size, in bits.
+const DEFAULT_BITS: u8 = 8;
+
+/// Lane widths of the phases following the command byte of a dual or quad SPI transaction.
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub struct Schedule {
+    /// Lanes carrying the address.
+    pub address_lanes: u8,
+    /// Size of the address, in bytes.
+    pub address_bytes: u8,
+    /// Clock cycles between the address and the data, mode bits included.
+    pub dummy_cycles: u8,
+    /// Lanes carrying the data read from the device.
+    pub data_lanes: u8,
+}
+impl Schedule {
+    pub fn new(address_lanes: u8, address_bytes: u8, dummy_cycles: u8, data_lanes: u8) -> Self {
+        Schedule {
+            address_lanes,
+            address_bytes,
+            dummy_cycles,
+            data_lanes,
+        }
+    }
+}
+
+/// Schedules of the dual and quad reads of SPI flashes.
+const FLASH_SCHEDULES: [(u8, Schedule); 4] = [
+    // 1-1-2
+    (
+        0x3B,
+        Schedule {
+            address_lanes: 1,
+            address_bytes: 3,
+            dummy_cycles: 8,
+            data_lanes: 2,
+        },
+    ),
+    // 1-1-4
+    (
+        0x6B,
+        Schedule {
+            address_lanes: 1,
+            address_bytes: 3,
+            dummy_cycles: 8,
+            data_lanes: 4,
+        },
+    ),
+    // 1-2-2, 4 cycles of mode bits
+    (
+        0xBB,
+        Schedule {
+            address_lanes: 2,
+            address_bytes: 3,
+            dummy_cycles: 4,
+            data_lanes: 2,
+        },
+    ),
+    // 1-4-4, 2 cycles of mode bits and 4 dummy cycles
+    (
+        0xEB,
+        Schedule {
+            address_lanes: 4,
+            address_bytes: 3,
+            dummy_cycles: 6,
+            data_lanes: 4,
+        },
+    ),
+];
+
+/// Timing and bus anomalies.
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub enum SpiWarning {
+    /// Data changed `margin` seconds before the sampling edge, less than the setup time.
+    Setup { margin: f64 },
+    /// Data changed `margin` seconds after the sampling edge, less than the hold time.
+    Hold { margin: f64 },
+    /// Chip select asserted while the clock is not at the idle level of the mode.
+    ClockIdleLevel,
+    /// Clock activity while the chip select is inactive.
+    ClockWithoutCs,
+}
+impl SpiWarning {
+    fn kind(&self) -> &'static str {
+        match self {
+            SpiWarning::Setup { .. } => "Setup",
+            SpiWarning::Hold { .. } => "Hold",
+            SpiWarning::ClockIdleLevel => "ClockIdleLevel",
+            SpiWarning::ClockWithoutCs => "ClockWithoutCs",
+        }
+    }
+    fn margin(&self) -> Option<f64> {
+        match *self {
+            SpiWarning::Setup { margin } | SpiWarning::Hold { margin } => Some(margin),
+            _ => None,
+        }
+    }
+}
+impl fmt::Display for SpiWarning {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            SpiWarning::Setup { margin } => {
+                write!(f, "Setup time violation ({:.1}ns)", margin * 1e9)
+            }
+            SpiWarning::Hold { margin } => write!(f, "Hold time violation ({:.1}ns)", margin * 1e9),
+            SpiWarning::ClockIdleLevel => {
+                write!(
+                    f,
+                    "Chip select asserted with the clock out of its idle level"
+                )
+            }
+            SpiWarning::ClockWithoutCs => write!(f, "Clock activity without chip select"),
+        }
+    }
 }
 
 #[derive(Debug)]
 pub enum SpiEvent {
     ChipSelect(bool),
-    Data { mosi: u8, miso: u8 },
+    Data {
+        mosi: u32,
+        miso: u32,
+    },
+    /// Bits shifted before the chip select was released in the middle of a word, in the low `bits`
+    /// bits of `mosi` and `miso`.
+    Partial {
+        mosi: u32,
+        miso: u32,
+        bits: u8,
+    },
+    Warning(SpiWarning),
+}
+impl Event for SpiEvent {
+    const LAYER: &'static str = "spi";
+    const CSV_HEADER: &'static [&'static str] =
+        &["cs", "mosi", "miso", "bits", "warning", "margin"];
+
+    fn kind(&self) -> &'static str {
+        match self {
+            SpiEvent::ChipSelect(_) => "ChipSelect",
+            SpiEvent::Data { .. } => "Data",
+            SpiEvent::Partial { .. } => "Partial",
+            SpiEvent::Warning(_) => "Warning",
+        }
+    }
+    fn fields(&self) -> Vec<(&'static str, Value)> {
+        match *self {
+            SpiEvent::ChipSelect(cs) => vec![("cs", Value::Bool(cs))],
+            SpiEvent::Data { mosi, miso } => {
+                vec![("mosi", Value::hex(mosi, 2)), ("miso", Value::hex(miso, 2))]
+            }
+            SpiEvent::Partial { mosi, miso, bits } => vec![
+                ("mosi", Value::hex(mosi, 2)),
+                ("miso", Value::hex(miso, 2)),
+                ("bits", Value::Int(bits.into())),
+            ],
+            SpiEvent::Warning(warning) => {
+                let mut fields = vec![("warning", Value::Str(warning.kind().to_string()))];
+                if let Some(margin) = warning.margin() {
+                    fields.push(("margin", Value::Float(margin)));
+                }
+                fields
+            }
+        }
+    }
+    fn csv_record(&self) -> Vec<Value> {
+        match *self {
+            SpiEvent::ChipSelect(cs) => vec![Value::Bool(cs)],
+            SpiEvent::Data { mosi, miso } => vec![
+                Value::Str(String::new()),
+                Value::hex(mosi, 2),
+                Value::hex(miso, 2),
+            ],
+            SpiEvent::Partial { mosi, miso, bits } => vec![
+                Value::Str(String::new()),
+                Value::hex(mosi, 2),
+                Value::hex(miso, 2),
+                Value::Int(bits.into()),
+            ],
+            SpiEvent::Warning(warning) => vec![
+                Value::Str(String::new()),
+                Value::Str(String::new()),
+                Value::Str(String::new()),
+                Value::Str(String::new()),
+                Value::Str(warning.kind().to_string()),
+                warning
+                    .margin()
+                    .map_or(Value::Str(String::new()), Value::Float),
+            ],
+        }
+    }
 }
 
 #[derive(Debug)]
@@ -38,22 +272,49 @@ pub struct SpiBuilder {
     mosi: u8,
     miso: u8,
     clk: u8,
-    phase: Phase,
-    polarity: Polarity,
+    mode: Mode,
     cs_active_level: Polarity,
+    idle_timeout: Option<f64>,
+    sdio: Option<(u8, u32)>,
+    min_pulse_width: f64,
+    setup_time: Option<f64>,
+    hold_time: Option<f64>,
+    bus_warnings: bool,
+    bits: u8,
+    lsb_first: bool,
+    io: Option<[u8; 2]>,
+    qpi: bool,
+    schedules: Vec<(u8, Schedule)>,
     inspect: bool,
+    format: Format,
+}
+impl Default for SpiBuilder {
+    fn default() -> Self {
+        Self::new()
+    }
 }
 impl SpiBuilder {
     pub fn new() -> Self {
         Self {
             cs: 0,
-            mosi: 1,
-            miso: 2,
+            miso: 1,
+            mosi: 2,
             clk: 3,
-            phase: Phase::FirstEdge,
-            polarity: Polarity::High,
+            mode: Mode::Mode0,
             cs_active_level: Polarity::Low,
+            idle_timeout: None,
+            sdio: None,
+            min_pulse_width: 0.,
+            setup_time: None,
+            hold_time: None,
+            bus_warnings: false,
+            bits: DEFAULT_BITS,
+            lsb_first: false,
+            io: None,
+            qpi: false,
+            schedules: FLASH_SCHEDULES.to_vec(),
             inspect: false,
+            format: Format::Text,
         }
     }
     pub fn cs(mut self, cs: u8) -> Self {
@@ -72,50 +333,403 @@ impl SpiBuilder {
         self.mosi = mosi;
         self
     }
-    pub fn mode(mut self, phase: Phase, polarity: Polarity) -> Self {
-        self.phase = phase;
-        self.polarity = polarity;
+    pub fn mode(mut self, mode: Mode) -> Self {
+        self.mode = mode;
         self
     }
     pub fn cs_active_level(mut self, cs_active_level: Polarity) -> Self {
         self.cs_active_level = cs_active_level;
         self
     }
+    /// Decodes a bus without chip select: a transaction starts on the first clock edge and ends
+    /// once the clock stayed idle for `timeout` seconds. Chip select events are reported at these
+    /// instants with the levels set by `cs_active_level`.
+    pub fn idle_timeout(mut self, timeout: f64) -> Self {
+        self.idle_timeout = Some(timeout);
+        self
+    }
+    /// Decodes a 3-wire bus: the first `turnaround` bits of a transaction are sent by the
+    /// controller (mosi) on the bidirectional `sdio` line, the following ones by the device (miso).
+    ///
+    /// Ignored in dual and quad mode.
+    pub fn sdio(mut self, sdio: u8, turnaround: u32) -> Self {
+        self.sdio = Some((sdio, turnaround));
+        self
+    }
+    /// Ignores the clock pulses shorter than `width` seconds, such as ringing on long leads.
+    pub fn min_pulse_width(mut self, width: f64) -> Self {
+        self.min_pulse_width = width;
+        self
+    }
+    /// Warns when data changes less than `setup_time` seconds before a sampling edge.
+    pub fn setup_time(mut self, setup_time: f64) -> Self {
+        self.setup_time = Some(setup_time);
+        self
+    }
+    /// Warns when data changes less than `hold_time` seconds after a sampling edge.
+    pub fn hold_time(mut self, hold_time: f64) -> Self {
+        self.hold_time = Some(hold_time);
+        self
+    }
+    /// Warns when the chip select is asserted while the clock is out of its idle level and on
+    /// clock activity while the chip select is inactive.
+    pub fn bus_warnings(mut self, bus_warnings: bool) -> Self {
+        self.bus_warnings = bus_warnings;
+        self
+    }
+    /// Sets the word size, from 4 to 32 bits.
+    pub fn bits(mut self, bits: u8) -> Self {
+        self.bits = bits;
+        self
+    }
+    /// Words are shifted least significant bit first.
+    pub fn lsb_first(mut self, lsb_first: bool) -> Self {
+        self.lsb_first = lsb_first;
+        self
+    }
+    /// Decodes dual and quad transfers: mosi and miso are IO0 and IO1, `io2` and `io3` the other
+    /// lanes. Words are bytes, the lanes of the phases following the command byte are given by
+    /// its schedule, transactions without one are decoded as single SPI.
+    pub fn qspi(mut self, io2: u8, io3: u8) -> Self {
+        self.io = Some([io2, io3]);
+        self
+    }
+    /// Commands are sent on 4 lanes, as well as the address and data of their schedule (4-4-4).
+    pub fn qpi(mut self, qpi: bool) -> Self {
+        self.qpi = qpi;
+        self
+    }
+    /// Sets the schedule of the transactions starting with `command`.
+    ///
+    /// The dual and quad reads of SPI flashes (0x3B, 0x6B, 0xBB and 0xEB) are scheduled by default.
+    pub fn schedule(mut self, command: u8, schedule: Schedule) -> Self {
+        self.schedules.retain(|&(cmd, _)| cmd != command);
+        self.schedules.push((command, schedule));
+        self
+    }
+    /// Sets the dummy cycles, mode bits included, of all the schedules.
+    pub fn dummy_cycles(mut self, dummy_cycles: u8) -> Self {
+        for (_, schedule) in &mut self.schedules {
+            schedule.dummy_cycles = dummy_cycles;
+        }
+        self
+    }
     pub fn inspect(mut self, inspect: bool) -> Self {
         self.inspect = inspect;
         self
     }
-    pub fn into_spi<T: Iterator<Item = Sample>>(self, it: T) -> Spi<T> {
-        Spi {
-            it: it,
+    pub fn format(mut self, format: Format) -> Self {
+        self.format = format;
+        self
+    }
+    /// Builds the decoder. This fails if a channel does not fit in a sample or if the word size is
+    /// out of range.
+    pub fn into_spi<T: Iterator<Item = Result<Sample, Error>>>(
+        self,
+        it: T,
+    ) -> Result<Spi<T>, Error> {
+        if !(4..=32).contains(&self.bits) {
+            return Err(Error::Config(format!(
+                "word size of {} bits, must be between 4 and 32",
+                self.bits
+            )));
+        }
+        let mut channels = vec![
+            ("cs", self.cs),
+            ("miso", self.miso),
+            ("mosi", self.mosi),
+            ("clk", self.clk),
+        ];
+        channels.extend(
+            self.io
+                .iter()
+                .flat_map(|&[io2, io3]| vec![("io2", io2), ("io3", io3)]),
+        );
+        channels.extend(self.sdio.map(|(sdio, _)| ("sdio", sdio)));
+        for (name, channel) in channels {
+            sample::check_channel(name, channel)?;
+        }
+
+        // lines sampled on the clock edges.
+        let data_lines = match (self.io, self.sdio) {
+            (Some([io2, io3]), _) => vec![self.mosi, self.miso, io2, io3],
+            (None, Some((sdio, _))) => vec![sdio],
+            (None, None) => vec![self.mosi, self.miso],
+        };
+        Ok(Spi {
+            it,
             inspect: self.inspect,
-            pending_event: None,
+            printer: Printer::new(self.format),
+            pending: VecDeque::new(),
 
             ccs: self.cs,
             cmiso: self.miso,
             cmosi: self.mosi,
             cclk: self.clk,
 
-            clk_phase: self.phase == Phase::SecondEdge,
-            clk_polarity: self.polarity == Polarity::Low,
             cs_active_level: self.cs_active_level == Polarity::High,
+            mode: self.mode,
+            idle_timeout: self.idle_timeout,
+            sdio: self.sdio,
+            glitch_filter: if self.min_pulse_width > 0. {
+                Some(GlitchFilter {
+                    min_width: self.min_pulse_width,
+                    clk_mask: 1 << self.clk,
+                    level: None,
+                    held: VecDeque::new(),
+                    released: VecDeque::new(),
+                })
+            } else {
+                None
+            },
+            setup_time: self.setup_time,
+            hold_time: self.hold_time,
+            bus_warnings: self.bus_warnings,
+            data_mask: data_lines.iter().fold(0, |mask, &ch| mask | 1 << ch),
+
+            qspi: self.io.map(|[io2, io3]| Qspi {
+                io: [self.mosi, self.miso, io2, io3],
+                command_lanes: if self.qpi { 4 } else { 1 },
+                schedules: self
+                    .schedules
+                    .iter()
+                    .map(|&(cmd, mut schedule)| {
+                        if self.qpi {
+                            schedule.address_lanes = 4;
+                            schedule.data_lanes = 4;
+                        }
+                        (cmd, schedule)
+                    })
+                    .collect(),
+                stage: Stage::Command,
+            }),
+            shift_reg: ShiftRegister {
+                bits: if self.io.is_some() { 8 } else { self.bits },
+                lsb_first: self.lsb_first,
+                mosi: 0,
+                miso: 0,
+                cnt: 0,
+            },
+            clk: self.mode.cpol(),
+            // without chip select, transactions start on the first clock edge.
+            cs: self.idle_timeout.is_some() && self.cs_active_level == Polarity::Low,
+            last_edge: 0.,
+            clocked: 0,
+            data: None,
+            data_changed: f64::NEG_INFINITY,
+            last_sampling_edge: None,
+            clock_without_cs: false,
+            timing: Timing::default(),
+            timings: None,
+        })
+    }
+}
+
+/// Shift registers of the mosi and miso lines.
+#[derive(Debug)]
+struct ShiftRegister {
+    bits: u8,
+    lsb_first: bool,
+    mosi: u32,
+    miso: u32,
+    cnt: u8,
+}
+impl ShiftRegister {
+    /// Shifts `lanes` bits of each direction in, returns the word once complete.
+    fn shift(&mut self, mosi: u32, miso: u32, lanes: u8) -> Option<SpiEvent> {
+        if self.lsb_first {
+            self.mosi |= mosi << self.cnt;
+            self.miso |= miso << self.cnt;
+        } else {
+            self.mosi = self.mosi.wrapping_shl(lanes.into()) | mosi;
+            self.miso = self.miso.wrapping_shl(lanes.into()) | miso;
+        }
+        self.cnt += lanes;
 
-            shift_cnt: 0,
-            shift_reg_mosi: 0,
-            shift_reg_miso: 0,
-            clk: false,
-            cs: false,
+        if self.cnt >= self.bits {
+            let (mosi, miso) = (self.mosi, self.miso);
+            self.reset();
+            Some(SpiEvent::Data { mosi, miso })
+        } else {
+            None
         }
     }
+    /// Drops the bits shifted so far, returns them if any.
+    fn reset(&mut self) -> Option<SpiEvent> {
+        let partial = if self.cnt != 0 {
+            Some(SpiEvent::Partial {
+                mosi: self.mosi,
+                miso: self.miso,
+                bits: self.cnt,
+            })
+        } else {
+            None
+        };
+        self.cnt = 0;
+        self.mosi = 0;
+        self.miso = 0;
+        partial
+    }
+}
+
+/// Phase of a dual or quad SPI transaction.
+#[derive(Debug, Copy, Clone, PartialEq)]
+enum Stage {
+    Command,
+    /// Address bytes left.
+    Address(Schedule, u8),
+    /// Dummy cycles left.
+    Dummy(Schedule, u8),
+    Data(Schedule),
+    /// Transaction without schedule.
+    Unscheduled,
+}
+
+/// Lanes and phases of dual and quad SPI transactions.
+#[derive(Debug)]
+struct Qspi {
+    /// Channels of IO0 to IO3.
+    io: [u8; 4],
+    command_lanes: u8,
+    schedules: Vec<(u8, Schedule)>,
+    stage: Stage,
+}
+impl Qspi {
+    /// Shifts the lanes of the current phase in, returns the word once complete.
+    fn clock(&mut self, sample: u64, shift_reg: &mut ShiftRegister) -> Option<SpiEvent> {
+        let io = self.io.iter().enumerate().fold(0, |io, (lane, &ch)| {
+            io | (((sample >> ch) & 1) as u32) << lane
+        });
+        // single lane phases shift mosi (IO0) and miso (IO1) in parallel.
+        let (mosi, miso, lanes) = match self.stage {
+            Stage::Command | Stage::Unscheduled if self.command_lanes == 1 => {
+                (io & 1, (io >> 1) & 1, 1)
+            }
+            Stage::Command | Stage::Unscheduled => (io, 0, 4),
+            Stage::Address(
+                Schedule {
+                    address_lanes: 1, ..
+                },
+                _,
+            ) => (io & 1, (io >> 1) & 1, 1),
+            Stage::Address(schedule, _) => (
+                io & lane_mask(schedule.address_lanes),
+                0,
+                schedule.address_lanes,
+            ),
+            Stage::Dummy(schedule, cycles) => {
+                self.stage = if cycles > 1 {
+                    Stage::Dummy(schedule, cycles - 1)
+                } else {
+                    Stage::Data(schedule)
+                };
+                return None;
+            }
+            Stage::Data(Schedule { data_lanes: 1, .. }) => (io & 1, (io >> 1) & 1, 1),
+            Stage::Data(schedule) => (0, io & lane_mask(schedule.data_lanes), schedule.data_lanes),
+        };
+        let word = shift_reg.shift(mosi, miso, lanes)?;
+
+        self.stage = match self.stage {
+            Stage::Command => {
+                let command = match word {
+                    SpiEvent::Data { mosi, .. } => mosi as u8,
+                    _ => return Some(word),
+                };
+                match self.schedules.iter().find(|&&(cmd, _)| cmd == command) {
+                    Some(&(_, schedule)) => Stage::Address(schedule, schedule.address_bytes),
+                    None => Stage::Unscheduled,
+                }
+            }
+            Stage::Address(schedule, bytes) => Stage::Address(schedule, bytes - 1),
+            stage => stage,
+        };
+        // phases left empty by the schedule are skipped.
+        if let Stage::Address(schedule, 0) = self.stage {
+            self.stage = Stage::Dummy(schedule, schedule.dummy_cycles);
+        }
+        if let Stage::Dummy(schedule, 0) = self.stage {
+            self.stage = Stage::Data(schedule);
+        }
+        Some(word)
+    }
+}
+
+/// Mask of the `lanes` low bits.
+fn lane_mask(lanes: u8) -> u32 {
+    (1 << lanes) - 1
+}
+
+/// Holds the samples following a clock transition until the clock stayed at its new level for
+/// the minimum pulse width. Shorter pulses are removed from the samples.
+#[derive(Debug)]
+struct GlitchFilter {
+    min_width: f64,
+    clk_mask: u64,
+    /// Clock level once filtered.
+    level: Option<bool>,
+    held: VecDeque<Sample>,
+    released: VecDeque<Sample>,
+}
+impl GlitchFilter {
+    fn push(&mut self, smp: Sample) {
+        let clk = smp.sample() & self.clk_mask != 0;
+        let edge = match self.held.front() {
+            Some(edge) => edge.timestamp(),
+            None if self.level.is_some() && self.level != Some(clk) => {
+                self.held.push_back(smp);
+                return;
+            }
+            None => {
+                self.level = Some(clk);
+                self.released.push_back(smp);
+                return;
+            }
+        };
+        if smp.timestamp() - edge >= self.min_width {
+            // the pulse is long enough, the sample is filtered against the new level.
+            self.flush();
+            self.push(smp);
+        } else if Some(clk) == self.level {
+            // glitch: the clock is restored in the held samples.
+            for held in self.held.drain(..) {
+                let sample = held.sample() ^ self.clk_mask;
+                self.released
+                    .push_back(Sample::new(sample, held.timestamp()));
+            }
+            self.released.push_back(smp);
+        } else {
+            self.held.push_back(smp);
+        }
+    }
+    /// Releases the held samples, their clock transition being kept.
+    fn flush(&mut self) {
+        if let Some(edge) = self.held.front() {
+            self.level = Some(edge.sample() & self.clk_mask != 0);
+        }
+        self.released.extend(self.held.drain(..));
+    }
+}
+
+/// Clock timing of a transaction.
+#[derive(Debug, Default)]
+struct Timing {
+    period_sum: f64,
+    periods: u32,
+    /// Time between the end of a word and the start of the next one.
+    gaps: Vec<f64>,
+    /// Time of the last sampling edge of the last word.
+    word_end: Option<f64>,
 }
 
 pub struct Spi<T>
 where
-    T: Iterator<Item = Sample>,
+    T: Iterator<Item = Result<Sample, Error>>,
 {
     it: T,
     inspect: bool,
-    pending_event: Option<(f64, SpiEvent)>,
+    printer: Printer,
+    pending: VecDeque<(f64, SpiEvent)>,
 
     ccs: u8,
     cmiso: u8,
@@ -123,134 +737,402 @@ where
     cclk: u8,
 
     cs_active_level: bool,
-    clk_phase: bool,
-    clk_polarity: bool,
+    mode: Mode,
+    idle_timeout: Option<f64>,
+    sdio: Option<(u8, u32)>,
+    glitch_filter: Option<GlitchFilter>,
+    setup_time: Option<f64>,
+    hold_time: Option<f64>,
+    bus_warnings: bool,
+    /// Channels sampled on the clock edges.
+    data_mask: u64,
 
-    shift_reg_mosi: u8,
-    shift_reg_miso: u8,
-    shift_cnt: u8,
+    qspi: Option<Qspi>,
+    shift_reg: ShiftRegister,
     clk: bool,
     cs: bool,
+    /// Time of the last clock edge.
+    last_edge: f64,
+    /// Bits clocked since the start of the transaction.
+    clocked: u32,
+    /// Level of the data lines.
+    data: Option<u64>,
+    /// Time of the last change of the data lines.
+    data_changed: f64,
+    last_sampling_edge: Option<f64>,
+    /// Clock activity was reported since the release of the chip select.
+    clock_without_cs: bool,
+    timing: Timing,
+    /// Timing of the transactions ended, recorded for [`SpiTransactions`].
+    timings: Option<VecDeque<Timing>>,
 }
 impl<T> fmt::Debug for Spi<T>
 where
-    T: Iterator<Item = Sample>,
+    T: Iterator<Item = Result<Sample, Error>>,
 {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
         write!(
             f,
-            "Spi {{ mosi: {:02x}, miso: {:02x}, cnt: {} }}",
-            self.shift_reg_mosi, self.shift_reg_miso, self.shift_cnt
+            "Spi {{ mosi: {:08x}, miso: {:08x}, cnt: {} }}",
+            self.shift_reg.mosi, self.shift_reg.miso, self.shift_reg.cnt
         )
     }
 }
-impl<T> Iterator for Spi<T>
+impl<T> Spi<T>
 where
-    T: Iterator<Item = Sample>,
+    T: Iterator<Item = Result<Sample, Error>>,
 {
-    type Item = (f64, SpiEvent);
+    /// Schedule followed by the transactions starting with `command`, none without `qspi`.
+    pub fn schedule(&self, command: u8) -> Option<Schedule> {
+        let qspi = self.qspi.as_ref()?;
+        qspi.schedules
+            .iter()
+            .find(|&&(cmd, _)| cmd == command)
+            .map(|&(_, schedule)| schedule)
+    }
 
-    fn next(&mut self) -> Option<Self::Item> {
-        let mut ret = None;
-        if self.pending_event.is_some() {
-            std::mem::swap(&mut ret, &mut self.pending_event);
-            return ret;
-        }
-        while let Some(smp) = self.it.next() {
-            let ts = smp.timestamp();
-            let sample = smp.sample();
-            let clk = ((sample >> self.cclk) & 1) == 1;
-            let cs = ((sample >> self.ccs) & 1) == 1;
+    fn warn(&mut self, ts: f64, warning: SpiWarning) {
+        self.pending.push_back((ts, SpiEvent::Warning(warning)));
+    }
+
+    /// Reads the next sample, through the glitch filter if any.
+    fn next_sample(&mut self) -> Option<Result<Sample, Error>> {
+        let filter = match self.glitch_filter {
+            Some(ref mut filter) => filter,
+            None => return self.it.next(),
+        };
+        loop {
+            if let Some(smp) = filter.released.pop_front() {
+                return Some(Ok(smp));
+            }
+            match self.it.next() {
+                Some(Ok(smp)) => filter.push(smp),
+                Some(Err(e)) => return Some(Err(e)),
+                None if filter.held.is_empty() => return None,
+                None => filter.flush(),
+            }
+        }
+    }
+
+    fn chip_select(&mut self, ts: f64, cs: bool) {
+        self.cs = cs;
+        self.clocked = 0;
+        self.clock_without_cs = false;
+        self.last_sampling_edge = None;
+        if let Some(ref mut qspi) = self.qspi {
+            qspi.stage = Stage::Command;
+        }
+        match self.shift_reg.reset() {
+            // the bits of an incomplete word are reported before the chip select.
+            Some(partial) if cs != self.cs_active_level => self.pending.push_back((ts, partial)),
+            _ => {}
+        }
+        let timing = std::mem::take(&mut self.timing);
+        if cs != self.cs_active_level {
+            if let Some(ref mut timings) = self.timings {
+                timings.push_back(timing);
+            }
+        }
+        self.pending.push_back((ts, SpiEvent::ChipSelect(cs)));
+    }
+
+    /// Ends the transaction of a bus without chip select once its clock stayed idle long enough.
+    fn check_idle(&mut self, ts: f64) {
+        if let Some(timeout) = self.idle_timeout {
+            if self.cs == self.cs_active_level && ts - self.last_edge >= timeout {
+                self.chip_select(self.last_edge + timeout, !self.cs_active_level);
+            }
+        }
+    }
 
+    fn update(&mut self, ts: f64, sample: u64) {
+        let clk = ((sample >> self.cclk) & 1) == 1;
+        // the first sample sets the initial state of the bus.
+        let first = self.data.is_none();
+        if self.idle_timeout.is_some() {
+            self.check_idle(ts);
+            if clk != self.clk && self.cs != self.cs_active_level {
+                self.chip_select(ts, self.cs_active_level);
+            }
+        } else {
+            let cs = ((sample >> self.ccs) & 1) == 1;
             if cs != self.cs {
-                self.cs = cs;
+                self.chip_select(ts, cs);
+                if self.bus_warnings
+                    && !first
+                    && cs == self.cs_active_level
+                    && clk != self.mode.cpol()
+                {
+                    self.warn(ts, SpiWarning::ClockIdleLevel);
+                }
+            }
+        }
 
-                ret = Some((ts, SpiEvent::ChipSelect(cs)));
-                if cs {
-                    self.shift_cnt = 0;
+        let data = sample & self.data_mask;
+        if self.data != Some(data) {
+            if !first && self.cs == self.cs_active_level {
+                match (self.hold_time, self.last_sampling_edge) {
+                    (Some(hold), Some(edge)) if ts - edge < hold => {
+                        self.warn(ts, SpiWarning::Hold { margin: ts - edge })
+                    }
+                    _ => {}
                 }
             }
-            if clk != self.clk {
-                self.clk = clk;
-                if cs == self.cs_active_level && clk != (self.clk_phase ^ self.clk_polarity) {
-                    self.shift_reg_mosi =
-                        self.shift_reg_mosi.wrapping_shl(1) | ((sample >> self.cmosi) & 1);
-                    self.shift_reg_miso =
-                        self.shift_reg_miso.wrapping_shl(1) | ((sample >> self.cmiso) & 1);
-                    self.shift_cnt += 1;
-
-                    if self.shift_cnt == 8 {
-                        self.shift_cnt = 0;
-
-                        let a = Some((
-                            ts,
-                            SpiEvent::Data {
-                                mosi: self.shift_reg_mosi,
-                                miso: self.shift_reg_miso,
-                            },
-                        ));
-                        if ret.is_some() {
-                            self.pending_event = a;
-                        } else {
-                            ret = a;
+            self.data = Some(data);
+            self.data_changed = ts;
+        }
+
+        if clk != self.clk {
+            self.clk = clk;
+            self.last_edge = ts;
+            if self.bus_warnings
+                && !first
+                && self.idle_timeout.is_none()
+                && self.cs != self.cs_active_level
+                && !self.clock_without_cs
+            {
+                self.clock_without_cs = true;
+                self.warn(ts, SpiWarning::ClockWithoutCs);
+            }
+            if self.cs == self.cs_active_level && clk == self.mode.sampling_level() {
+                let previous_edge = self.last_sampling_edge.replace(ts);
+                // dummy cycles keep the clock running between words without shifting data.
+                let dummy = self
+                    .qspi
+                    .as_ref()
+                    .is_some_and(|qspi| matches!(qspi.stage, Stage::Dummy(..)));
+                match (self.shift_reg.cnt, previous_edge) {
+                    // clock periods are measured within words, gaps between them.
+                    (0, _) if !dummy => {
+                        if let Some(end) = self.timing.word_end {
+                            self.timing.gaps.push(ts - end);
                         }
                     }
+                    (_, Some(previous)) => {
+                        self.timing.period_sum += ts - previous;
+                        self.timing.periods += 1;
+                    }
+                    _ => {}
                 }
-            }
-            if self.inspect {
-                if let Some((ref ts, ref ev)) = ret {
-                    println!("{:.6} {:?}", ts, ev);
+                match self.setup_time {
+                    Some(setup) if ts - self.data_changed < setup => self.warn(
+                        ts,
+                        SpiWarning::Setup {
+                            margin: ts - self.data_changed,
+                        },
+                    ),
+                    _ => {}
+                }
+                let bit = |ch: u8| ((sample >> ch) & 1) as u32;
+                let data = match (&mut self.qspi, self.sdio) {
+                    (Some(qspi), _) => qspi.clock(sample, &mut self.shift_reg),
+                    (None, Some((sdio, turnaround))) if self.clocked < turnaround => {
+                        self.shift_reg.shift(bit(sdio), 0, 1)
+                    }
+                    (None, Some((sdio, _))) => self.shift_reg.shift(0, bit(sdio), 1),
+                    (None, None) => self.shift_reg.shift(bit(self.cmosi), bit(self.cmiso), 1),
+                };
+                self.clocked = self.clocked.saturating_add(1);
+                if dummy {
+                    self.timing.word_end = Some(ts);
+                }
+                if let Some(data) = data {
+                    self.timing.word_end = Some(ts);
+                    self.pending.push_back((ts, data));
                 }
             }
-            if ret.is_some() {
-                break;
+        }
+    }
+}
+
+impl<T> Iterator for Spi<T>
+where
+    T: Iterator<Item = Result<Sample, Error>>,
+{
+    type Item = Result<(f64, SpiEvent), Error>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        while self.pending.is_empty() {
+            match self.next_sample() {
+                Some(Ok(smp)) => self.update(smp.timestamp(), smp.sample()),
+                Some(Err(e)) => return Some(Err(e)),
+                None => {
+                    // the last transaction of a bus without chip select ends with the input.
+                    self.check_idle(f64::INFINITY);
+                    if self.pending.is_empty() {
+                        return None;
+                    }
+                }
             }
         }
-        ret
+        let (ts, ev) = self.pending.pop_front()?;
+        if self.inspect {
+            self.printer.print(ts, &ev);
+        }
+        Some(Ok((ts, ev)))
     }
 }
 
-impl<T> Spi<SampleIterator<T>>
+/// Data exchanged while the chip select was asserted.
+///
+/// Words wider than a byte are stored most significant byte first, partial words are dropped.
+#[derive(Clone, PartialEq)]
+pub struct SpiTransaction {
+    pub start: f64,
+    pub end: f64,
+    pub mosi: Vec<u8>,
+    pub miso: Vec<u8>,
+    /// Clock frequency measured within the words.
+    pub clock_hz: Option<f64>,
+    /// Time between the last sampling edge of a word and the first one of the next word: a clock
+    /// period for back to back words.
+    pub gaps: Vec<f64>,
+}
+impl SpiTransaction {
+    fn new(start: f64) -> Self {
+        SpiTransaction {
+            start,
+            end: start,
+            mosi: Vec::new(),
+            miso: Vec::new(),
+            clock_hz: None,
+            gaps: Vec::new(),
+        }
+    }
+}
+impl fmt::Debug for SpiTransaction {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "Transaction {{ end: {:.6}, clock: ", self.end)?;
+        match self.clock_hz {
+            Some(hz) => write!(f, "{:.3}MHz", hz / 1e6)?,
+            None => write!(f, "-")?,
+        }
+        write!(f, ", mosi({:4}): ", self.mosi.len())?;
+        for b in &self.mosi {
+            write!(f, "{:02x}", b)?;
+        }
+        write!(f, ", miso({:4}): ", self.miso.len())?;
+        for b in &self.miso {
+            write!(f, "{:02x}", b)?;
+        }
+        let max_gap = self.gaps.iter().cloned().fold(0., f64::max);
+        write!(f, ", max gap: {:.1}ns }}", max_gap * 1e9)
+    }
+}
+impl Event for SpiTransaction {
+    const LAYER: &'static str = "spi";
+    const CSV_HEADER: &'static [&'static str] = &["end", "clock_hz", "mosi", "miso", "gaps"];
+
+    fn kind(&self) -> &'static str {
+        "Transaction"
+    }
+    fn fields(&self) -> Vec<(&'static str, Value)> {
+        vec![
+            ("end", Value::Float(self.end)),
+            ("clock_hz", Value::Float(self.clock_hz.unwrap_or(f64::NAN))),
+            ("mosi", Value::hex_bytes(&self.mosi)),
+            ("miso", Value::hex_bytes(&self.miso)),
+            ("gaps", Value::List(self.gaps.clone())),
+        ]
+    }
+    fn csv_record(&self) -> Vec<Value> {
+        vec![
+            Value::Float(self.end),
+            self.clock_hz
+                .map_or(Value::Str(String::new()), Value::Float),
+            Value::hex_bytes(&self.mosi),
+            Value::hex_bytes(&self.miso),
+            Value::List(self.gaps.clone()),
+        ]
+    }
+}
+
+/// Groups the events of an SPI decoder by transaction.
+pub struct SpiTransactions<T>
 where
-    T: 'static + std::io::Read,
+    T: Iterator<Item = Result<Sample, Error>>,
 {
-    pub fn new<'a>(input: T, matches: &ArgMatches<'a>, depth: u64) -> Spi<SampleIterator<T>> {
-        let it = SampleIterator::new(input, matches, depth + 1);
-        let (phase, polarity) = match value_t!(matches, "mode", u8).unwrap_or_else(|e| e.exit()) {
-            1 => (Phase::SecondEdge, Polarity::High),
-            2 => (Phase::FirstEdge, Polarity::Low),
-            3 => (Phase::SecondEdge, Polarity::Low),
-            0 | _ => (Phase::FirstEdge, Polarity::High),
-        };
+    spi: Spi<T>,
+    inspect: bool,
+    printer: Printer,
+    current: Option<SpiTransaction>,
+}
+impl<T> SpiTransactions<T>
+where
+    T: Iterator<Item = Result<Sample, Error>>,
+{
+    pub fn from_spi(mut spi: Spi<T>, inspect: bool) -> Self {
+        spi.timings = Some(VecDeque::new());
+        Self {
+            spi,
+            inspect,
+            printer: Printer::new(Format::Text),
+            current: None,
+        }
+    }
+    pub fn format(mut self, format: Format) -> Self {
+        self.printer = Printer::new(format);
+        self
+    }
+
+    fn push(&mut self, ts: f64, mosi: u32, miso: u32) {
+        let bytes = usize::from(self.spi.shift_reg.bits.div_ceil(8));
+        if let Some(ref mut transaction) = self.current {
+            transaction.end = ts;
+            transaction
+                .mosi
+                .extend_from_slice(&mosi.to_be_bytes()[4 - bytes..]);
+            transaction
+                .miso
+                .extend_from_slice(&miso.to_be_bytes()[4 - bytes..]);
+        }
+    }
+    fn finish(&mut self, ts: f64, timing: Option<Timing>) -> Option<(f64, SpiTransaction)> {
+        let mut transaction = self.current.take()?;
+        transaction.end = ts;
+        if let Some(timing) = timing {
+            if timing.periods != 0 {
+                transaction.clock_hz = Some(f64::from(timing.periods) / timing.period_sum);
+            }
+            transaction.gaps = timing.gaps;
+        }
+        if self.inspect {
+            self.printer.print(transaction.start, &transaction);
+        }
+        Some((transaction.start, transaction))
+    }
+}
+impl<T> Iterator for SpiTransactions<T>
+where
+    T: Iterator<Item = Result<Sample, Error>>,
+{
+    type Item = Result<(f64, SpiTransaction), Error>;
 
-        SpiBuilder::new()
-            .cs(value_t!(matches, "cs", u8).unwrap_or_else(|e| e.exit()))
-            .miso(value_t!(matches, "miso", u8).unwrap_or_else(|e| e.exit()))
-            .mosi(value_t!(matches, "mosi", u8).unwrap_or_else(|e| e.exit()))
-            .clk(value_t!(matches, "clk", u8).unwrap_or_else(|e| e.exit()))
-            .mode(phase, polarity)
-            .cs_active_level(
-                value_t!(matches, "cs_active_level", Polarity).unwrap_or_else(|e| e.exit()),
-            )
-            .inspect(matches.occurrences_of("v") >= depth)
-            .into_spi(it)
-    }
-}
-pub fn args() -> [Arg<'static, 'static>; 6] {
-    [
-        Arg::from_usage("--cs [cs] 'Channel used for the chip select.'").default_value("0"),
-        Arg::from_usage("--miso [miso] 'Channel used for miso'").default_value("1"),
-        Arg::from_usage("--mosi [mosi] 'Channel used for mosi'").default_value("2"),
-        Arg::from_usage("--clk [clk] 'Channel used for the clock'").default_value("3"),
-        Arg::from_usage("-l, --cs_active_level [cs_active_level] 'Chip select active level'")
-            .possible_values(&["High", "Low"])
-            .default_value("Low"),
-        Arg::from_usage("-m --mode [mode] 'Spi mode'")
-            .possible_values(&["0", "1", "2", "3"])
-            .default_value("0"),
-    ]
-}
-
-pub fn subcommand() -> App<'static, 'static> {
-    SubCommand::with_name("spi").args(&args())
+    fn next(&mut self) -> Option<Self::Item> {
+        loop {
+            let (ts, ev) = match self.spi.next() {
+                Some(Ok(ev)) => ev,
+                Some(Err(e)) => return Some(Err(e)),
+                None => {
+                    // a transaction left open by the end of the input.
+                    let timing = std::mem::take(&mut self.spi.timing);
+                    let end = self.current.as_ref()?.end;
+                    return self.finish(end, Some(timing)).map(Ok);
+                }
+            };
+            match ev {
+                SpiEvent::ChipSelect(cs) if cs == self.spi.cs_active_level => {
+                    self.current = Some(SpiTransaction::new(ts));
+                }
+                SpiEvent::ChipSelect(_) => {
+                    let timing = self.spi.timings.as_mut().and_then(VecDeque::pop_front);
+                    if let Some(transaction) = self.finish(ts, timing) {
+                        return Some(Ok(transaction));
+                    }
+                }
+                SpiEvent::Data { mosi, miso } => self.push(ts, mosi, miso),
+                SpiEvent::Partial { .. } | SpiEvent::Warning(_) => {}
+            }
+        }
+    }
 }
@@ -1,6 +1,7 @@
-use crate::sample::SampleIterator;
-use crate::spi::{self, SpiEvent};
-use clap::{App, ArgMatches, SubCommand};
+use crate::error::Error;
+use crate::output::{Event, Format, Printer, Value};
+use crate::sample::Sample;
+use crate::spi::{Spi, SpiEvent};
 use std::fmt;
 
 struct DebugVec<'a>(&'a Vec<u8>);
@@ -14,8 +15,8 @@ impl<'a> fmt::Debug for DebugVec<'a> {
 }
 
 pub struct Read {
-    addr: u32,
-    data: Vec<u8>,
+    pub addr: u32,
+    pub data: Vec<u8>,
 }
 impl Read {
     fn new() -> Read {
@@ -38,8 +39,8 @@ impl fmt::Debug for Read {
 }
 
 pub struct PageProgram {
-    addr: u32,
-    data: Vec<u8>,
+    pub addr: u32,
+    pub data: Vec<u8>,
 }
 impl PageProgram {
     fn new() -> PageProgram {
@@ -61,19 +62,19 @@ impl fmt::Debug for PageProgram {
     }
 }
 
-pub struct SFDP {
-    addr: u32,
-    data: Vec<u8>,
+pub struct Sfdp {
+    pub addr: u32,
+    pub data: Vec<u8>,
 }
-impl SFDP {
+impl Sfdp {
     fn new() -> Self {
-        SFDP {
+        Sfdp {
             addr: 0,
             data: Vec::new(),
         }
     }
 }
-impl fmt::Debug for SFDP {
+impl fmt::Debug for Sfdp {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
         write!(
             f,
@@ -87,12 +88,12 @@ impl fmt::Debug for SFDP {
 
 #[derive(Debug, Copy, Clone)]
 pub struct DeviceId {
-    manufacturer: u8,
-    device_id: u16,
+    pub manufacturer: u8,
+    pub device_id: u16,
 }
 
 #[derive(Debug)]
-pub struct StatusRegister(u8);
+pub struct StatusRegister(pub u8);
 
 pub enum Command {
     Read(Read),
@@ -103,7 +104,7 @@ pub enum Command {
     BlockErase(u32),
     BlockErase32(u32),
     SectorErase(u32),
-    ReadSFDP(SFDP),
+    ReadSfdp(Sfdp),
     ReadStatusRegister(StatusRegister),
     ReadDeviceId(DeviceId),
 }
@@ -118,13 +119,89 @@ impl fmt::Debug for Command {
             Command::BlockErase(addr) => write!(f, "BlockErase({:x})", addr),
             Command::BlockErase32(addr) => write!(f, "BlockErase32({:x})", addr),
             Command::SectorErase(addr) => write!(f, "SectorErase({:x})", addr),
-            Command::ReadSFDP(sfdp) => sfdp.fmt(f),
+            Command::ReadSfdp(sfdp) => sfdp.fmt(f),
             Command::ReadStatusRegister(sr) => sr.fmt(f),
             Command::ReadDeviceId(did) => did.fmt(f),
         }
     }
 }
 
+impl Event for Command {
+    const LAYER: &'static str = "spif";
+    const CSV_HEADER: &'static [&'static str] = &["command", "addr", "len", "data"];
+
+    fn kind(&self) -> &'static str {
+        match self {
+            Command::Read(_) => "Read",
+            Command::WriteEnable => "WriteEnable",
+            Command::ResetEnable => "ResetEnable",
+            Command::Reset => "Reset",
+            Command::PageProgram(_) => "PageProgram",
+            Command::BlockErase(_) => "BlockErase",
+            Command::BlockErase32(_) => "BlockErase32",
+            Command::SectorErase(_) => "SectorErase",
+            Command::ReadSfdp(_) => "ReadSFDP",
+            Command::ReadStatusRegister(_) => "ReadStatusRegister",
+            Command::ReadDeviceId(_) => "ReadDeviceId",
+        }
+    }
+    fn fields(&self) -> Vec<(&'static str, Value)> {
+        let transfer = |addr: u32, data: &[u8]| {
+            vec![
+                ("addr", Value::hex(addr, 6)),
+                ("len", Value::Int(data.len() as u64)),
+                ("data", Value::hex_bytes(data)),
+            ]
+        };
+        match self {
+            Command::Read(r) => transfer(r.addr, &r.data),
+            Command::PageProgram(pp) => transfer(pp.addr, &pp.data),
+            Command::ReadSfdp(sfdp) => transfer(sfdp.addr, &sfdp.data),
+            Command::BlockErase(addr)
+            | Command::BlockErase32(addr)
+            | Command::SectorErase(addr) => {
+                vec![("addr", Value::hex(*addr, 6))]
+            }
+            Command::ReadStatusRegister(sr) => vec![("status", Value::hex(sr.0, 2))],
+            Command::ReadDeviceId(did) => vec![
+                ("manufacturer", Value::hex(did.manufacturer, 2)),
+                ("device_id", Value::hex(did.device_id, 4)),
+            ],
+            Command::WriteEnable | Command::ResetEnable | Command::Reset => vec![],
+        }
+    }
+    fn csv_record(&self) -> Vec<Value> {
+        let mut record = vec![Value::Str(self.kind().to_string())];
+        let (addr, data) = match self {
+            Command::Read(r) => (Some(r.addr), r.data.clone()),
+            Command::PageProgram(pp) => (Some(pp.addr), pp.data.clone()),
+            Command::ReadSfdp(sfdp) => (Some(sfdp.addr), sfdp.data.clone()),
+            Command::BlockErase(addr)
+            | Command::BlockErase32(addr)
+            | Command::SectorErase(addr) => (Some(*addr), vec![]),
+            Command::ReadStatusRegister(sr) => (None, vec![sr.0]),
+            Command::ReadDeviceId(did) => (
+                None,
+                vec![
+                    did.manufacturer,
+                    (did.device_id >> 8) as u8,
+                    did.device_id as u8,
+                ],
+            ),
+            Command::WriteEnable | Command::ResetEnable | Command::Reset => (None, vec![]),
+        };
+        record.push(match addr {
+            Some(addr) => Value::hex(addr, 6),
+            None => Value::Str(String::new()),
+        });
+        if !data.is_empty() {
+            record.push(Value::Int(data.len() as u64));
+            record.push(Value::hex_bytes(&data));
+        }
+        record
+    }
+}
+
 enum PartialCommand {
     Read(f64, Read),
     ReadStatusRegister(f64),
@@ -132,16 +209,17 @@ enum PartialCommand {
     BlockErase(f64, u32),
     BlockErase32(f64, u32),
     SectorErase(f64, u32),
-    ReadSFDP(f64, SFDP),
+    ReadSfdp(f64, Sfdp),
     ReadDeviceId(f64, DeviceId),
     None,
 }
 pub struct Spif<T>
 where
-    T: Iterator<Item = (f64, SpiEvent)>,
+    T: Iterator<Item = Result<Sample, Error>>,
 {
-    it: T,
+    it: Spi<T>,
     inspect: bool,
+    printer: Printer,
 
     cs: bool,
     idx: u32,
@@ -150,9 +228,25 @@ where
 
 impl<T> Spif<T>
 where
-    T: Iterator<Item = (f64, SpiEvent)>,
+    T: Iterator<Item = Result<Sample, Error>>,
 {
-    fn new_cmd(&mut self, ts: f64, mosi: u8, miso: u8) -> Result<Option<Command>, String> {
+    /// Decodes SPI flash commands from the events of an SPI decoder.
+    pub fn from_spi(it: Spi<T>, inspect: bool) -> Self {
+        Self {
+            it,
+            inspect,
+            printer: Printer::new(Format::Text),
+            cs: false,
+            idx: 0,
+            partial: PartialCommand::None,
+        }
+    }
+    pub fn format(mut self, format: Format) -> Self {
+        self.printer = Printer::new(format);
+        self
+    }
+
+    fn new_cmd(&mut self, ts: f64, mosi: u8, miso: u8) -> Result<Option<Command>, Error> {
         self.idx = 0;
         match mosi {
             0x02 => {
@@ -163,6 +257,11 @@ where
                 self.partial = PartialCommand::Read(ts, Read::new());
                 Ok(None)
             }
+            // dual and quad reads need the spi decoder to follow their lane schedule.
+            0x3B | 0x6B | 0xBB | 0xEB if self.it.schedule(mosi).is_some() => {
+                self.partial = PartialCommand::Read(ts, Read::new());
+                Ok(None)
+            }
             0x05 => {
                 self.partial = PartialCommand::ReadStatusRegister(ts);
                 Ok(None)
@@ -177,7 +276,7 @@ where
                 Ok(None)
             }
             0x5A => {
-                self.partial = PartialCommand::ReadSFDP(ts, SFDP::new());
+                self.partial = PartialCommand::ReadSfdp(ts, Sfdp::new());
                 Ok(None)
             }
             0x66 => Ok(Some(Command::ResetEnable)),
@@ -197,11 +296,14 @@ where
                 Ok(None)
             }
 
-            _ => Err(format!("{:.6} Unsupported cmd {:x}-{:x}", ts, mosi, miso)),
+            _ => Err(Error::protocol(
+                ts,
+                format!("Unsupported cmd {:x}-{:x}", mosi, miso),
+            )),
         }
     }
 
-    fn update(&mut self, ts: f64, ev: SpiEvent) -> Result<Option<(f64, Command)>, String> {
+    fn update(&mut self, ts: f64, ev: SpiEvent) -> Result<Option<(f64, Command)>, Error> {
         match ev {
             SpiEvent::ChipSelect(false) => {
                 self.cs = false;
@@ -217,154 +319,138 @@ where
                     PartialCommand::PageProgram(sts, pp) => {
                         Ok(Some((sts, Command::PageProgram(pp))))
                     }
-                    PartialCommand::ReadSFDP(sts, sfdp) => Ok(Some((sts, Command::ReadSFDP(sfdp)))),
+                    PartialCommand::ReadSfdp(sts, sfdp) => Ok(Some((sts, Command::ReadSfdp(sfdp)))),
                     _ => Ok(None),
                 }
             }
-            SpiEvent::Data { mosi, miso } if !self.cs => match self.partial {
-                PartialCommand::None => match self.new_cmd(ts, mosi, miso) {
-                    Ok(Some(cmd)) => Ok(Some((ts, cmd))),
-                    Ok(None) => Ok(None),
-                    Err(msg) => Err(msg),
-                },
-                PartialCommand::Read(_, ref mut r) => {
-                    if self.idx < 3 {
-                        r.addr = (r.addr << 8) | (mosi as u32);
-                        self.idx += 1;
-                    } else {
-                        r.data.push(miso);
-                    }
-                    Ok(None)
-                }
-                PartialCommand::ReadStatusRegister(sts) => {
-                    self.partial = PartialCommand::None;
-                    Ok(Some((
-                        sts,
-                        Command::ReadStatusRegister(StatusRegister(miso)),
-                    )))
-                }
-                PartialCommand::BlockErase(ref sts, ref mut addr) => {
-                    let mut res = None;
-                    if self.idx < 2 {
-                        *addr = (*addr << 8) | (mosi as u32);
-                        self.idx += 1;
-                    } else {
-                        res = Some((*sts, Command::BlockErase((*addr << 8) | (mosi as u32))));
-                        self.partial = PartialCommand::None;
+            SpiEvent::Data { mosi, miso } if !self.cs => {
+                // flash commands are made of bytes.
+                let (mosi, miso) = (mosi as u8, miso as u8);
+                match self.partial {
+                    PartialCommand::None => match self.new_cmd(ts, mosi, miso) {
+                        Ok(Some(cmd)) => Ok(Some((ts, cmd))),
+                        Ok(None) => Ok(None),
+                        Err(e) => Err(e),
+                    },
+                    PartialCommand::Read(_, ref mut r) => {
+                        if self.idx < 3 {
+                            r.addr = (r.addr << 8) | (mosi as u32);
+                            self.idx += 1;
+                        } else {
+                            r.data.push(miso);
+                        }
+                        Ok(None)
                     }
-                    Ok(res)
-                }
-                PartialCommand::BlockErase32(ref sts, ref mut addr) => {
-                    let mut res = None;
-                    if self.idx < 2 {
-                        *addr = (*addr << 8) | (mosi as u32);
-                        self.idx += 1;
-                    } else {
-                        res = Some((*sts, Command::BlockErase32((*addr << 8) | (mosi as u32))));
+                    PartialCommand::ReadStatusRegister(sts) => {
                         self.partial = PartialCommand::None;
+                        Ok(Some((
+                            sts,
+                            Command::ReadStatusRegister(StatusRegister(miso)),
+                        )))
                     }
-                    Ok(res)
-                }
-                PartialCommand::SectorErase(ref sts, ref mut addr) => {
-                    let mut res = None;
-                    if self.idx < 2 {
-                        *addr = (*addr << 8) | (mosi as u32);
-                        self.idx += 1;
-                    } else {
-                        res = Some((*sts, Command::SectorErase((*addr << 8) | (mosi as u32))));
-                        self.partial = PartialCommand::None;
+                    PartialCommand::BlockErase(ref sts, ref mut addr) => {
+                        let mut res = None;
+                        if self.idx < 2 {
+                            *addr = (*addr << 8) | (mosi as u32);
+                            self.idx += 1;
+                        } else {
+                            res = Some((*sts, Command::BlockErase((*addr << 8) | (mosi as u32))));
+                            self.partial = PartialCommand::None;
+                        }
+                        Ok(res)
                     }
-                    Ok(res)
-                }
-                PartialCommand::PageProgram(_, ref mut pp) => {
-                    if self.idx < 3 {
-                        pp.addr = (pp.addr << 8) | (mosi as u32);
-                        self.idx += 1;
-                    } else {
-                        pp.data.push(mosi);
+                    PartialCommand::BlockErase32(ref sts, ref mut addr) => {
+                        let mut res = None;
+                        if self.idx < 2 {
+                            *addr = (*addr << 8) | (mosi as u32);
+                            self.idx += 1;
+                        } else {
+                            res = Some((*sts, Command::BlockErase32((*addr << 8) | (mosi as u32))));
+                            self.partial = PartialCommand::None;
+                        }
+                        Ok(res)
                     }
-                    Ok(None)
-                }
-                PartialCommand::ReadSFDP(_, ref mut sfdp) => {
-                    if self.idx < 3 {
-                        sfdp.addr = (sfdp.addr << 8) | (mosi as u32);
-                        self.idx += 1;
-                    } else {
-                        sfdp.data.push(miso);
+                    PartialCommand::SectorErase(ref sts, ref mut addr) => {
+                        let mut res = None;
+                        if self.idx < 2 {
+                            *addr = (*addr << 8) | (mosi as u32);
+                            self.idx += 1;
+                        } else {
+                            res = Some((*sts, Command::SectorErase((*addr << 8) | (mosi as u32))));
+                            self.partial = PartialCommand::None;
+                        }
+                        Ok(res)
                     }
-                    Ok(None)
-                }
-                PartialCommand::ReadDeviceId(ref sts, ref mut rdid) => {
-                    let mut res = None;
-                    match self.idx {
-                        0 => {
-                            rdid.manufacturer = miso;
-                            self.idx += 1
+                    PartialCommand::PageProgram(_, ref mut pp) => {
+                        if self.idx < 3 {
+                            pp.addr = (pp.addr << 8) | (mosi as u32);
+                            self.idx += 1;
+                        } else {
+                            pp.data.push(mosi);
                         }
-                        1 => {
-                            rdid.device_id = (miso as u16) << 8;
-                            self.idx += 1
+                        Ok(None)
+                    }
+                    PartialCommand::ReadSfdp(_, ref mut sfdp) => {
+                        if self.idx < 3 {
+                            sfdp.addr = (sfdp.addr << 8) | (mosi as u32);
+                            self.idx += 1;
+                        } else {
+                            sfdp.data.push(miso);
                         }
-                        2 => {
-                            rdid.device_id |= miso as u16;
-                            res = Some((*sts, Command::ReadDeviceId(*rdid)));
-                            self.partial = PartialCommand::None;
+                        Ok(None)
+                    }
+                    PartialCommand::ReadDeviceId(ref sts, ref mut rdid) => {
+                        let mut res = None;
+                        match self.idx {
+                            0 => {
+                                rdid.manufacturer = miso;
+                                self.idx += 1
+                            }
+                            1 => {
+                                rdid.device_id = (miso as u16) << 8;
+                                self.idx += 1
+                            }
+                            2 => {
+                                rdid.device_id |= miso as u16;
+                                res = Some((*sts, Command::ReadDeviceId(*rdid)));
+                                self.partial = PartialCommand::None;
+                            }
+                            _ => unreachable!(),
                         }
-                        _ => unreachable!(),
+                        Ok(res)
                     }
-                    Ok(res)
                 }
-            },
-            _ => Err(format!("Ignoring event: {:?} at {:.6}", ev, ts)),
+            }
+            SpiEvent::Warning(warning) => Err(Error::protocol(ts, warning.to_string())),
+            _ => Err(Error::protocol(ts, format!("Ignoring event: {:?}", ev))),
         }
     }
 }
 
 impl<T> Iterator for Spif<T>
 where
-    T: Iterator<Item = (f64, SpiEvent)>,
+    T: Iterator<Item = Result<Sample, Error>>,
 {
-    type Item = Result<(f64, Command), String>;
+    type Item = Result<(f64, Command), Error>;
 
     fn next(&mut self) -> Option<Self::Item> {
-        while let Some((ts, ev)) = self.it.next() {
+        loop {
+            let (ts, ev) = match self.it.next()? {
+                Ok(ev) => ev,
+                Err(e) => return Some(Err(e)),
+            };
             match self.update(ts, ev) {
                 Ok(None) => {}
                 Ok(Some((ts, cmd))) => {
                     if self.inspect {
-                        println!("{:.6} {:?}", ts, cmd);
+                        self.printer.print(ts, &cmd);
                     }
                     return Some(Ok((ts, cmd)));
                 }
-                Err(msg) => {
-                    return Some(Err(msg));
+                Err(e) => {
+                    return Some(Err(e));
                 }
             }
         }
-        None
     }
 }
-
-impl<T> Spif<spi::Spi<SampleIterator<T>>>
-where
-    T: 'static + std::io::Read,
-{
-    pub fn new<'a>(
-        input: T,
-        matches: &ArgMatches<'a>,
-        depth: u64,
-    ) -> Spif<spi::Spi<SampleIterator<T>>> {
-        let inspect = matches.occurrences_of("v") >= depth;
-        let it = spi::Spi::new(input, &matches, depth + 1);
-        Self {
-            it,
-            inspect,
-            cs: false,
-            idx: 0,
-            partial: PartialCommand::None,
-        }
-    }
-}
-pub fn subcommand() -> App<'static, 'static> {
-    SubCommand::with_name("spif").args(&spi::args())
-}
@@ -0,0 +1,260 @@
+use crate::error::Error;
+use crate::sample::{Sample, MAX_CHANNELS};
+use std::io::Read;
+use zip::read::read_zipfile_from_stream;
+
+/// Parses a sigrok session file (`.sr`).
+///
+/// The file is a zip archive holding a `metadata` file (sample rate, probe names, sample size)
+/// followed by the `logic-1-N` chunks of dense samples. The archive is read as a stream so the
+/// `metadata` must come before the data, as written by sigrok.
+/// Only the samples changing the state of a channel are produced.
+pub struct SrParser<T>
+where
+    T: Read,
+{
+    input: T,
+    metadata: Option<Metadata>,
+    map: Vec<(String, u8)>,
+    /// Channel of each probe, in the order of the bits of a sample.
+    channels: Vec<Option<u8>>,
+    chunk: Vec<u8>,
+    pos: usize,
+    index: u64,
+    state: Option<u64>,
+    done: bool,
+}
+
+/// Content of the `[device 1]` section of the metadata.
+struct Metadata {
+    capturefile: String,
+    samplerate: f64,
+    unitsize: usize,
+    probes: Vec<String>,
+}
+impl Metadata {
+    fn parse(metadata: &str) -> Result<Self, Error> {
+        let mut capturefile = None;
+        let mut samplerate = None;
+        let mut unitsize = 1;
+        let mut probes = Vec::new();
+        let mut in_device = false;
+        for line in metadata.lines().map(str::trim) {
+            if line.starts_with('[') {
+                in_device = line == "[device 1]";
+                continue;
+            }
+            let (key, value) = match line.split_once('=') {
+                Some((key, value)) if in_device => (key.trim(), value.trim()),
+                _ => continue,
+            };
+            match key {
+                "capturefile" => capturefile = Some(value.to_string()),
+                "samplerate" => samplerate = Some(parse_samplerate(value)?),
+                "unitsize" => {
+                    unitsize = value
+                        .parse()
+                        .ok()
+                        .filter(|&size| size > 0 && size <= 8)
+                        .ok_or_else(|| Error::Sigrok(format!("unsupported unitsize {}", value)))?
+                }
+                _ => {
+                    if let Some(probe) = key.strip_prefix("probe") {
+                        let idx = probe
+                            .parse::<usize>()
+                            .map_err(|_| Error::Sigrok(format!("invalid probe key '{}'", key)))?;
+                        if idx == 0 || idx > usize::from(MAX_CHANNELS) {
+                            return Err(Error::Sigrok(format!("invalid probe key '{}'", key)));
+                        }
+                        if probes.len() < idx {
+                            probes.resize(idx, String::new());
+                        }
+                        probes[idx - 1] = value.to_string();
+                    }
+                }
+            }
+        }
+        Ok(Metadata {
+            capturefile: capturefile
+                .ok_or_else(|| Error::Sigrok("no logic capture in the session".to_string()))?,
+            samplerate: samplerate
+                .ok_or_else(|| Error::Sigrok("unknown sample rate".to_string()))?,
+            unitsize,
+            probes,
+        })
+    }
+}
+
+/// Parses `24 MHz`, `500 kHz`, `1000000`, ...
+fn parse_samplerate(samplerate: &str) -> Result<f64, Error> {
+    let idx = samplerate
+        .find(|c: char| !c.is_ascii_digit() && c != '.')
+        .unwrap_or(samplerate.len());
+    let factor = match samplerate[idx..].trim() {
+        "" | "Hz" => 1.,
+        "kHz" => 1e3,
+        "MHz" => 1e6,
+        "GHz" => 1e9,
+        _ => {
+            return Err(Error::Sigrok(format!(
+                "invalid samplerate '{}'",
+                samplerate
+            )))
+        }
+    };
+    match samplerate[..idx].parse::<f64>() {
+        Ok(rate) if rate > 0. => Ok(rate * factor),
+        _ => Err(Error::Sigrok(format!(
+            "invalid samplerate '{}'",
+            samplerate
+        ))),
+    }
+}
+
+impl<T> SrParser<T>
+where
+    T: Read,
+{
+    pub fn new(input: T) -> Self {
+        Self {
+            input,
+            metadata: None,
+            map: Vec::new(),
+            channels: Vec::new(),
+            chunk: Vec::new(),
+            pos: 0,
+            index: 0,
+            state: None,
+            done: false,
+        }
+    }
+    /// Assigns `channel` to the probe named `name`.
+    ///
+    /// Without a map, probe `N` (starting at 1) is channel `N - 1`. Once a probe is mapped, the
+    /// probes that are not mapped are ignored.
+    pub fn map_probe(mut self, name: &str, channel: u8) -> Self {
+        self.map.push((name.to_string(), channel));
+        self
+    }
+
+    /// Returns the sample rate of the session, reading its metadata if not done yet.
+    pub fn samplerate(&mut self) -> Result<f64, Error> {
+        if self.metadata.is_none() {
+            if let Err(e) = self.parse_metadata() {
+                self.done = true;
+                return Err(e);
+            }
+        }
+        Ok(self.metadata.as_ref().map_or(1., |m| m.samplerate))
+    }
+
+    /// Reads the archive up to the `metadata` file.
+    fn parse_metadata(&mut self) -> Result<(), Error> {
+        let mut metadata = String::new();
+        loop {
+            let mut file = match read_zipfile_from_stream(&mut self.input)? {
+                Some(file) => file,
+                None => return Err(Error::Sigrok("no metadata in the session".to_string())),
+            };
+            if file.name() == "metadata" {
+                file.read_to_string(&mut metadata)?;
+                break;
+            }
+        }
+        let metadata = Metadata::parse(&metadata)?;
+
+        self.channels = (0..metadata.unitsize * 8)
+            .map(|bit| match metadata.probes.get(bit) {
+                Some(name) if !self.map.is_empty() => self
+                    .map
+                    .iter()
+                    .find(|(probe, _)| probe == name)
+                    .map(|&(_, channel)| channel),
+                Some(_) => Some(bit as u8),
+                None if metadata.probes.is_empty() => Some(bit as u8),
+                None => None,
+            })
+            .collect();
+        if let Some((name, _)) = self
+            .map
+            .iter()
+            .find(|(name, _)| !metadata.probes.contains(name))
+        {
+            return Err(Error::UnknownSignal(name.clone()));
+        }
+        self.metadata = Some(metadata);
+        Ok(())
+    }
+
+    /// Loads the next chunk of samples, returns false at the end of the archive.
+    fn next_chunk(&mut self, capturefile: &str) -> Result<bool, Error> {
+        while let Some(mut file) = read_zipfile_from_stream(&mut self.input)? {
+            let name = file.name();
+            // chunks are named `logic-1-N`, older versions use a single `logic-1` file.
+            if name == capturefile
+                || name
+                    .strip_prefix(capturefile)
+                    .and_then(|n| n.strip_prefix('-'))
+                    .is_some_and(|n| n.parse::<u32>().is_ok())
+            {
+                self.chunk.clear();
+                self.pos = 0;
+                file.read_to_end(&mut self.chunk)?;
+                return Ok(true);
+            }
+        }
+        Ok(false)
+    }
+}
+
+impl<T> Iterator for SrParser<T>
+where
+    T: Read,
+{
+    type Item = Result<Sample, Error>;
+    fn next(&mut self) -> Option<Result<Sample, Error>> {
+        if self.done {
+            return None;
+        }
+        if let Err(e) = self.samplerate() {
+            return Some(Err(e));
+        }
+        let (unitsize, samplerate) = match self.metadata {
+            Some(ref m) => (m.unitsize, m.samplerate),
+            None => return None,
+        };
+        loop {
+            while self.pos + unitsize <= self.chunk.len() {
+                let mut raw = [0; 8];
+                raw[..unitsize].copy_from_slice(&self.chunk[self.pos..self.pos + unitsize]);
+                let raw = u64::from_le_bytes(raw);
+                self.pos += unitsize;
+                self.index += 1;
+
+                let state = self
+                    .channels
+                    .iter()
+                    .enumerate()
+                    .filter_map(|(bit, ch)| ch.map(|ch| ((raw >> bit) & 1) << ch))
+                    .fold(0, |state, bit| state | bit);
+                if self.state != Some(state) {
+                    self.state = Some(state);
+                    let ts = (self.index - 1) as f64 / samplerate;
+                    return Some(Ok(Sample::new(state, ts)));
+                }
+            }
+            let capturefile = self.metadata.as_ref()?.capturefile.clone();
+            match self.next_chunk(&capturefile) {
+                Ok(true) => {}
+                Ok(false) => {
+                    self.done = true;
+                    return None;
+                }
+                Err(e) => {
+                    self.done = true;
+                    return Some(Err(e));
+                }
+            }
+        }
+    }
+}
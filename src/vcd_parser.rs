@@ -1,19 +1,130 @@
-use crate::sample::Sample;
-use std::collections::BTreeMap;
-use std::io::Read;
-use vcd::{Command, IdCode, Parser, TimescaleUnit, Value, VarType};
+use crate::error::Error;
+use crate::sample::{Sample, MAX_CHANNELS};
+use std::collections::{BTreeMap, VecDeque};
+use std::io::{self, BufReader, Bytes, ErrorKind, Read};
+use std::str::FromStr;
+use vcd::{Command, IdCode, Parser, TimescaleUnit, Value};
+
+/// Variable types holding logic levels.
+const LOGIC_TYPES: &[&str] = &[
+    "wire", "reg", "logic", "integer", "bit", "tri", "tri0", "tri1", "triand", "trior", "trireg",
+    "wand", "wor", "supply0", "supply1",
+];
+
+/// How undefined (`x`) and high impedance (`z`) values are handled.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Undefined {
+    /// The channel is low.
+    Zero,
+    /// The channel keeps its previous level.
+    Last,
+    /// The channel keeps its previous level and an `UndefinedValue` error is reported.
+    Warn,
+}
+impl FromStr for Undefined {
+    type Err = String;
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "zero" => Ok(Undefined::Zero),
+            "last" => Ok(Undefined::Last),
+            "warn" => Ok(Undefined::Warn),
+            _ => Err(format!("Unknown undefined value policy: {}", s)),
+        }
+    }
+}
+
+/// A channel assigned to a VCD signal.
+struct Mapping {
+    path: String,
+    channel: usize,
+    found: bool,
+}
+impl Mapping {
+    /// Matches `path` against the full hierarchical name of a signal or against its last elements.
+    fn matches(&self, full: &str) -> bool {
+        full == self.path
+            || (full.ends_with(&self.path) && full[..full.len() - self.path.len()].ends_with('.'))
+    }
+}
+
+/// A variable declared in the header.
+struct VarDef {
+    /// Full hierarchical name, without the bit range.
+    name: String,
+    size: usize,
+    /// Index of each bit in the declared range, least significant first.
+    indexes: Vec<i64>,
+}
+impl VarDef {
+    /// Parses the arguments of a `$var` command: type, size, identifier, name and optional range.
+    fn parse(scope: &[String], args: &[String]) -> Result<(String, IdCode, VarDef), Error> {
+        if args.len() < 4 {
+            return Err(invalid_data("incomplete $var command"));
+        }
+        let size = args[1]
+            .parse::<usize>()
+            .map_err(|_| invalid_data("invalid $var size"))?;
+        let id = IdCode::from_str(&args[2]).map_err(|_| invalid_data("invalid $var identifier"))?;
+        // the range may be glued to the name (`data[7:0]`) or not (`data [7:0]`).
+        let reference = args[3..].concat();
+        let (reference, range) = match reference.find('[') {
+            Some(idx) => (&reference[..idx], Some(&reference[idx..])),
+            None => (&reference[..], None),
+        };
+        let (msb, lsb) = match range.map(parse_range) {
+            Some(Some(range)) => range,
+            Some(None) => return Err(Error::BadVarName(args[3..].join(" "))),
+            None => (size as i64 - 1, 0),
+        };
+        let step = if msb >= lsb { 1 } else { -1 };
+
+        let mut name = scope.to_vec();
+        // a single bit selected from a vector keeps its index in its name.
+        name.push(match range {
+            Some(range) if size == 1 && msb == lsb => format!("{}{}", reference, range),
+            _ => reference.to_string(),
+        });
+        let var = VarDef {
+            name: name.join("."),
+            size,
+            indexes: (0..size as i64).map(|k| lsb + k * step).collect(),
+        };
+        Ok((args[0].clone(), id, var))
+    }
+}
+
+/// Parses `[msb:lsb]` or `[bit]`.
+fn parse_range(range: &str) -> Option<(i64, i64)> {
+    let range = range.strip_prefix('[')?.strip_suffix(']')?;
+    match range.split_once(':') {
+        Some((msb, lsb)) => Some((msb.trim().parse().ok()?, lsb.trim().parse().ok()?)),
+        None => {
+            let bit = range.trim().parse().ok()?;
+            Some((bit, bit))
+        }
+    }
+}
+
+/// A variable assigned to channels.
+struct Var {
+    name: String,
+    /// Bit of the value (least significant first) and channel it is assigned to.
+    bits: Vec<(usize, usize)>,
+}
 
 pub struct VcdParser<T>
 where
     T: Read,
 {
-    input: Parser<T>,
+    header: Option<BufReader<T>>,
+    input: Option<Parser<BufReader<T>>>,
     factor: f64,
-    first_ts: f64,
     current_ts: f64,
-    vars: BTreeMap<IdCode, usize>,
-    state: u8,
-    stopped: bool,
+    map: Vec<Mapping>,
+    vars: BTreeMap<IdCode, Var>,
+    undefined: Undefined,
+    pending: VecDeque<Result<Sample, Error>>,
+    state: u64,
 }
 
 impl<T> VcdParser<T>
@@ -22,82 +133,252 @@ where
 {
     pub fn new(input: T) -> Self {
         Self {
-            input: Parser::new(input),
+            header: Some(BufReader::new(input)),
+            input: None,
             factor: 1.,
-            first_ts: -0.1, // pre-trigger buffer size
-            current_ts: -0.1,
+            current_ts: 0.,
+            map: Vec::new(),
             vars: BTreeMap::new(),
+            undefined: Undefined::Warn,
+            pending: VecDeque::new(),
             state: 0,
-            stopped: false,
         }
     }
+    /// Assigns `channel` to the signal `path` (e.g. `top.spi.sck`).
+    ///
+    /// The path is made of the scopes and the name of the signal separated by dots, leading scopes
+    /// can be omitted. A single bit of a vector is selected with its index (e.g. `top.data[3]`),
+    /// the bits of a whole vector are assigned to consecutive channels starting with its least
+    /// significant bit. Once a signal is mapped, the signals that are not mapped are ignored.
+    pub fn map_signal(mut self, path: &str, channel: u8) -> Self {
+        self.map.push(Mapping {
+            path: path.to_string(),
+            channel: usize::from(channel),
+            found: false,
+        });
+        self
+    }
+    /// Sets how undefined and high impedance values are handled (default: `Undefined::Warn`).
+    pub fn undefined(mut self, undefined: Undefined) -> Self {
+        self.undefined = undefined;
+        self
+    }
+
+    /// Reads the declarations up to `$enddefinitions`.
+    ///
+    /// The header is parsed here rather than by the `vcd` crate which rejects types such as
+    /// `logic` and vector ranges.
+    fn parse_header(&mut self, reader: &mut BufReader<T>) -> Result<(), Error> {
+        let mut bytes = reader.by_ref().bytes();
+        let mut scope = Vec::new();
+        loop {
+            let cmd = match next_token(&mut bytes)? {
+                Some(cmd) => cmd,
+                None => return Err(io::Error::from(ErrorKind::UnexpectedEof).into()),
+            };
+            let mut args = Vec::new();
+            loop {
+                match next_token(&mut bytes)? {
+                    Some(ref tok) if tok == "$end" => break,
+                    Some(tok) => args.push(tok),
+                    None => return Err(io::Error::from(ErrorKind::UnexpectedEof).into()),
+                }
+            }
+            match cmd.as_str() {
+                "$timescale" => self.factor = parse_timescale(&args.concat())?,
+                "$scope" => scope.push(args.get(1).cloned().unwrap_or_default()),
+                "$upscope" => {
+                    scope.pop();
+                }
+                "$var" => match VarDef::parse(&scope, &args) {
+                    Ok((ty, id, def)) => self.define(&ty, id, def),
+                    Err(e) => self.pending.push_back(Err(e)),
+                },
+                "$enddefinitions" => break,
+                cmd if cmd.starts_with('$') => {}
+                _ => return Err(invalid_data("unexpected token in VCD header")),
+            }
+        }
+        if let Some(m) = self.map.iter().find(|m| !m.found) {
+            self.pending
+                .push_back(Err(Error::UnknownSignal(m.path.clone())));
+        }
+        Ok(())
+    }
+
+    /// Assigns channels to a variable: from the channel map if any or from the digits ending its
+    /// name.
+    fn define(&mut self, ty: &str, id: IdCode, def: VarDef) {
+        let mut bits = Vec::new();
+        if self.map.is_empty() {
+            let base = def.name.rsplit('.').next().unwrap_or("");
+            let digits = base.len() - base.trim_end_matches(|c: char| c.is_ascii_digit()).len();
+            match base[base.len() - digits..].parse::<usize>() {
+                Ok(channel) => bits.extend((0..def.size).map(|bit| (bit, channel + bit))),
+                Err(_) => {
+                    self.pending.push_back(Err(Error::BadVarName(def.name)));
+                    return;
+                }
+            }
+        } else {
+            for m in self.map.iter_mut() {
+                if m.matches(&def.name) {
+                    bits.extend((0..def.size).map(|bit| (bit, m.channel + bit)));
+                } else if let Some(bit) = def
+                    .indexes
+                    .iter()
+                    .position(|idx| m.matches(&format!("{}[{}]", def.name, idx)))
+                {
+                    bits.push((bit, m.channel));
+                } else {
+                    continue;
+                }
+                m.found = true;
+            }
+            if bits.is_empty() {
+                return;
+            }
+        }
+
+        if !LOGIC_TYPES.contains(&ty) {
+            self.pending
+                .push_back(Err(Error::UnsupportedVarType(ty.to_string())));
+        } else if bits.iter().any(|&(_, ch)| ch >= usize::from(MAX_CHANNELS)) {
+            self.pending.push_back(Err(Error::BadVarName(def.name)));
+        } else {
+            let var = self.vars.entry(id).or_insert_with(|| Var {
+                name: def.name,
+                bits: Vec::new(),
+            });
+            var.bits.extend(bits);
+        }
+    }
+
+    /// Applies a value change, `values` are most significant bit first.
+    fn change(&mut self, id: IdCode, values: &[Value]) {
+        // changes of variables that could not be mapped are ignored.
+        let var = match self.vars.get(&id) {
+            Some(var) => var,
+            None => return,
+        };
+        let mut undefined = false;
+        for &(bit, channel) in &var.bits {
+            // shorter values are extended with 0, or with x/z when they are the leftmost bit.
+            let value = match values.len().checked_sub(bit + 1) {
+                Some(idx) => values[idx],
+                None => match values.first() {
+                    Some(&Value::X) => Value::X,
+                    Some(&Value::Z) => Value::Z,
+                    _ => Value::V0,
+                },
+            };
+            match (value, self.undefined) {
+                (Value::V0, _) | (Value::X, Undefined::Zero) | (Value::Z, Undefined::Zero) => {
+                    self.state &= !(1 << channel)
+                }
+                (Value::V1, _) => self.state |= 1 << channel,
+                (_, Undefined::Last) => {}
+                (_, Undefined::Warn) => undefined = true,
+            }
+        }
+        if undefined {
+            self.pending.push_back(Err(Error::UndefinedValue {
+                ts: self.current_ts,
+                signal: var.name.clone(),
+            }));
+        }
+        self.pending
+            .push_back(Ok(Sample::new(self.state, self.current_ts)));
+    }
+}
+
+fn invalid_data(msg: &str) -> Error {
+    io::Error::new(ErrorKind::InvalidData, msg).into()
+}
+
+/// Reads the next whitespace separated token.
+fn next_token<R: Read>(bytes: &mut Bytes<R>) -> Result<Option<String>, Error> {
+    let mut token = Vec::new();
+    for b in bytes {
+        match b? {
+            b' ' | b'\n' | b'\r' | b'\t' if token.is_empty() => {}
+            b' ' | b'\n' | b'\r' | b'\t' => break,
+            b => token.push(b),
+        }
+    }
+    if token.is_empty() {
+        return Ok(None);
+    }
+    String::from_utf8(token)
+        .map(Some)
+        .map_err(|_| invalid_data("VCD header is not UTF-8"))
+}
+
+/// Parses `1ns`, `100 ps`, ... into a factor to seconds.
+fn parse_timescale(timescale: &str) -> Result<f64, Error> {
+    let idx = timescale
+        .find(|c: char| !c.is_ascii_digit())
+        .unwrap_or(timescale.len());
+    let n = timescale[..idx]
+        .parse::<u32>()
+        .map_err(|_| invalid_data("invalid timescale"))?;
+    let unit = TimescaleUnit::from_str(&timescale[idx..])
+        .map_err(|_| invalid_data("invalid timescale unit"))?;
+    Ok(f64::from(n)
+        * match unit {
+            TimescaleUnit::S => 1.,
+            TimescaleUnit::MS => 0.001,
+            TimescaleUnit::US => 0.000001,
+            TimescaleUnit::NS => 0.000000001,
+            TimescaleUnit::PS => 0.000000000001,
+            TimescaleUnit::FS => 0.000000000000001,
+        })
 }
 
 impl<T> Iterator for VcdParser<T>
 where
     T: Read,
 {
-    type Item = Result<Sample, String>;
-    fn next(&mut self) -> Option<Result<Sample, String>> {
-        if self.stopped {
-            return None;
+    type Item = Result<Sample, Error>;
+    fn next(&mut self) -> Option<Result<Sample, Error>> {
+        if let Some(mut reader) = self.header.take() {
+            if let Err(e) = self.parse_header(&mut reader) {
+                return Some(Err(e));
+            }
+            self.input = Some(Parser::new(reader));
         }
-        while let Some(res) = self.input.next() {
-            match res {
-                Ok(cmd) => match cmd {
-                    Command::Timescale(n, unit) => {
-                        self.factor = (n as f64)
-                            * match unit {
-                                TimescaleUnit::S => 1.,
-                                TimescaleUnit::MS => 0.001,
-                                TimescaleUnit::US => 0.000001,
-                                TimescaleUnit::NS => 0.000000001,
-                                TimescaleUnit::PS => 0.000000000001,
-                                TimescaleUnit::FS => 0.000000000000001,
-                            };
-                    }
-                    Command::Timestamp(ts) => {
-                        let new_ts = (ts as f64) * self.factor;
-                        if self.first_ts == -0.1 {
-                            self.first_ts = new_ts;
-                        }
-                        let new_ts = new_ts - self.first_ts - 0.1;
-                        assert!(self.current_ts <= new_ts, "Timestamp must be monotonic");
-                        self.current_ts = new_ts;
-                    }
-                    Command::ChangeScalar(id, v) => {
-                        let v = match v {
-                            Value::V0 => 0,
-                            Value::V1 => 1,
-                            _ => {
-                                self.stopped = true;
-                                return Some(Err(format!("Unsupported value : {:?}", v)));
-                            }
-                        };
-                        let shift = self.vars[&id];
-                        self.state &= !(1 << shift);
-                        self.state |= v << shift;
-                        return Some(Ok(Sample::new(self.state, self.current_ts)));
-                    }
-                    Command::VarDef(ty, _sz, id, name) => {
-                        if ty == VarType::Wire {
-                            self.vars.insert(
-                                id,
-                                name.split('_').nth(1).unwrap().parse::<usize>().unwrap(),
-                            );
-                        } else {
-                            return Some(Err(format!("Unsupported VarType: {:?}", ty)));
-                        }
+        loop {
+            if let Some(res) = self.pending.pop_front() {
+                return Some(res);
+            }
+            let cmd = match self.input.as_mut()?.next()? {
+                Ok(cmd) => cmd,
+                // a malformed command is skipped, the input is dropped on any other error.
+                Err(err) => {
+                    if err.kind() != ErrorKind::InvalidData {
+                        self.input = None;
                     }
-                    _v => {
-                        //eprintln!("ignoring: {:?}", v);
+                    return Some(Err(err.into()));
+                }
+            };
+            match cmd {
+                Command::Timestamp(ts) => {
+                    let new_ts = (ts as f64) * self.factor;
+                    let previous = self.current_ts;
+                    self.current_ts = new_ts;
+                    if previous > new_ts {
+                        return Some(Err(Error::NonMonotonicTimestamp {
+                            previous,
+                            current: new_ts,
+                        }));
                     }
-                },
-                Err(err) => {
-                    return Some(Err(format!("{:?}", err)));
+                }
+                Command::ChangeScalar(id, v) => self.change(id, &[v]),
+                Command::ChangeVector(id, v) => self.change(id, &v),
+                _v => {
+                    //eprintln!("ignoring: {:?}", v);
                 }
             }
         }
-        None
     }
 }
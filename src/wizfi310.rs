@@ -1,6 +1,6 @@
-use crate::sample::SampleIterator;
-use crate::serial::{self, SerialError, SerialEvent};
-use clap::{App, Arg, ArgMatches, SubCommand};
+use crate::error::Error;
+use crate::output::{Event, Format, Printer, Value};
+use crate::serial::SerialEvent;
 use std::net::Ipv4Addr;
 
 #[derive(Debug)]
@@ -11,19 +11,67 @@ pub enum WizFi310Event {
     Recv(RecvHeader, String),
     Resp(String),
 }
+impl Event for WizFi310Event {
+    const LAYER: &'static str = "wizfi310";
+    const CSV_HEADER: &'static [&'static str] = &["event", "socket_id", "ip", "port", "data"];
+
+    fn kind(&self) -> &'static str {
+        match self {
+            WizFi310Event::Greeting(_) => "Greeting",
+            WizFi310Event::Command(_) => "Command",
+            WizFi310Event::Sent(_) => "Sent",
+            WizFi310Event::Recv(_, _) => "Recv",
+            WizFi310Event::Resp(_) => "Resp",
+        }
+    }
+    fn fields(&self) -> Vec<(&'static str, Value)> {
+        match self {
+            WizFi310Event::Greeting(s)
+            | WizFi310Event::Command(s)
+            | WizFi310Event::Sent(s)
+            | WizFi310Event::Resp(s) => vec![("data", Value::Str(s.clone()))],
+            WizFi310Event::Recv(header, s) => vec![
+                ("socket_id", Value::Int(u64::from(header.socket_id))),
+                ("ip", Value::Str(header.ip.to_string())),
+                ("port", Value::Int(u64::from(header.port))),
+                ("data", Value::Str(s.clone())),
+            ],
+        }
+    }
+    fn csv_record(&self) -> Vec<Value> {
+        let empty = || Value::Str(String::new());
+        let mut record = vec![Value::Str(self.kind().to_string())];
+        match self {
+            WizFi310Event::Recv(header, s) => record.extend(vec![
+                Value::Int(u64::from(header.socket_id)),
+                Value::Str(header.ip.to_string()),
+                Value::Int(u64::from(header.port)),
+                Value::Str(s.clone()),
+            ]),
+            WizFi310Event::Greeting(s)
+            | WizFi310Event::Command(s)
+            | WizFi310Event::Sent(s)
+            | WizFi310Event::Resp(s) => {
+                record.extend(vec![empty(), empty(), empty(), Value::Str(s.clone())])
+            }
+        }
+        record
+    }
+}
 #[derive(Debug)]
 pub struct RecvHeader {
-    socket_id: u8,
-    ip: Ipv4Addr,
-    port: u16,
+    pub socket_id: u8,
+    pub ip: Ipv4Addr,
+    pub port: u16,
 }
 
 pub struct Wizfi310<T>
 where
-    T: Iterator<Item = (f64, SerialEvent)>,
+    T: Iterator<Item = Result<(f64, SerialEvent), Error>>,
 {
     it: T,
     inspect: bool,
+    printer: Printer,
     data_to_send: usize,
     data_to_receive: usize,
     recv_header: Option<RecvHeader>,
@@ -32,17 +80,64 @@ where
     rx: String,
 }
 
+impl<T> Wizfi310<T>
+where
+    T: Iterator<Item = Result<(f64, SerialEvent), Error>>,
+{
+    /// Decodes the WizFi310 AT command set from the events of a serial decoder.
+    pub fn from_serial(it: T, inspect: bool) -> Self {
+        Self {
+            it,
+            inspect,
+            printer: Printer::new(Format::Text),
+            data_to_send: 0,
+            data_to_receive: 0,
+            recv_header: None,
+            tx: String::new(),
+            rx: String::new(),
+        }
+    }
+    pub fn format(mut self, format: Format) -> Self {
+        self.printer = Printer::new(format);
+        self
+    }
+    /// Returns the underlying serial decoder.
+    pub fn get_ref(&self) -> &T {
+        &self.it
+    }
+}
+
+/// Parses a `{socket_id,ip,port,length}` receive header.
+fn parse_recv_header(header: &str) -> Option<(RecvHeader, usize)> {
+    let mut hsplit = header
+        .trim_start_matches('{')
+        .trim_end_matches('}')
+        .split(',');
+    let event = RecvHeader {
+        socket_id: hsplit.next()?.parse().ok()?,
+        ip: hsplit.next()?.parse().ok()?,
+        port: hsplit.next()?.parse().ok()?,
+    };
+    let len = hsplit.next()?.parse().ok()?;
+    Some((event, len))
+}
+
 impl<T> Iterator for Wizfi310<T>
 where
-    T: Iterator<Item = (f64, SerialEvent)>,
+    T: Iterator<Item = Result<(f64, SerialEvent), Error>>,
 {
-    type Item = (f64, WizFi310Event);
+    type Item = Result<(f64, WizFi310Event), Error>;
 
     fn next(&mut self) -> Option<Self::Item> {
         let mut res = None;
-        while let Some((ts, ev)) = self.it.next() {
+        for ev in self.it.by_ref() {
+            let (ts, ev) = match ev {
+                Ok(ev) => ev,
+                Err(e) => return Some(Err(e)),
+            };
             match ev {
                 SerialEvent::Tx(c) => {
+                    let c = c as u8;
                     self.tx.push(c as char);
 
                     if self.data_to_send != 0 {
@@ -51,15 +146,16 @@ where
 
                             let mut v = String::new();
                             std::mem::swap(&mut v, &mut self.tx);
-                            res = Some((ts, WizFi310Event::Sent(v)));
+                            res = Some(Ok((ts, WizFi310Event::Sent(v))));
                         }
                     } else if (c as char) == '\r' {
                         let mut v = String::new();
                         std::mem::swap(&mut v, &mut self.tx);
-                        res = Some((ts, WizFi310Event::Command(v)));
+                        res = Some(Ok((ts, WizFi310Event::Command(v))));
                     }
                 }
                 SerialEvent::Rx(c) => {
+                    let c = c as u8;
                     self.rx.push(c as char);
 
                     if self.data_to_receive != 0 {
@@ -68,38 +164,46 @@ where
 
                             let mut v = String::new();
                             std::mem::swap(&mut v, &mut self.rx);
-                            res = Some((
-                                ts,
-                                WizFi310Event::Recv(self.recv_header.take().unwrap(), v),
-                            ));
+                            res = Some(match self.recv_header.take() {
+                                Some(header) => Ok((ts, WizFi310Event::Recv(header, v))),
+                                None => Err(Error::protocol(ts, "Data received without header")),
+                            });
                         }
                     } else if (c as char) == '\n' {
-                        if self.rx.starts_with("[") && self.rx.ends_with("]\r\n") {
-                            if self.rx.contains(",") {
-                                let line: String =
-                                    self.rx.chars().skip(1).take(self.rx.len() - 4).collect();
-                                self.data_to_send =
-                                    line.split(',').last().and_then(|v| v.parse().ok()).unwrap()
+                        if self.rx.starts_with('[')
+                            && self.rx.ends_with("]\r\n")
+                            && self.rx.contains(',')
+                        {
+                            let line: String =
+                                self.rx.chars().skip(1).take(self.rx.len() - 4).collect();
+                            match line.split(',').next_back().and_then(|v| v.parse().ok()) {
+                                Some(len) => self.data_to_send = len,
+                                None => {
+                                    res = Some(Err(Error::protocol(
+                                        ts,
+                                        format!("Invalid send length: {:?}", line),
+                                    )))
+                                }
                             }
                         }
                         let mut v = String::new();
                         std::mem::swap(&mut v, &mut self.rx);
-                        res = Some((ts, WizFi310Event::Resp(v)));
+                        if res.is_none() {
+                            res = Some(Ok((ts, WizFi310Event::Resp(v))));
+                        }
                     } else if (c as char) == '}' {
-                        let header = self
-                            .rx
-                            .chars()
-                            .skip(1)
-                            .take(self.rx.len() - 2)
-                            .collect::<String>();
-                        let mut hsplit = header.split(',');
-                        let event = RecvHeader {
-                            socket_id: hsplit.next().and_then(|v| v.parse().ok()).unwrap(),
-                            ip: hsplit.next().and_then(|v| v.parse().ok()).unwrap(),
-                            port: hsplit.next().and_then(|v| v.parse().ok()).unwrap(),
-                        };
-                        self.data_to_receive = hsplit.next().and_then(|v| v.parse().ok()).unwrap();
-                        self.recv_header = Some(event);
+                        match parse_recv_header(&self.rx) {
+                            Some((header, len)) => {
+                                self.data_to_receive = len;
+                                self.recv_header = Some(header);
+                            }
+                            None => {
+                                res = Some(Err(Error::protocol(
+                                    ts,
+                                    format!("Invalid receive header: {:?}", self.rx),
+                                )))
+                            }
+                        }
                         self.rx.clear();
                     }
                 }
@@ -112,46 +216,14 @@ where
             //
 
             if self.inspect {
-                if let Some((ref ts, ref s)) = res {
-                    println!("{:.6} {:?}", ts, s);
+                if let Some(Ok((ts, ref ev))) = res {
+                    self.printer.print(ts, ev);
                 };
             }
-            if let Some((_, ref res)) = res {
-                match res {
-                    WizFi310Event::Command(s) => {}
-                    WizFi310Event::Resp(s) => {}
-                    _ => {}
-                }
+            if res.is_some() {
                 break;
             }
         }
         res
     }
 }
-
-impl<T> Wizfi310<serial::Serial<SampleIterator<T>>>
-where
-    T: 'static + std::io::Read,
-{
-    pub fn new<'a>(
-        input: T,
-        matches: &ArgMatches<'a>,
-        depth: u64,
-    ) -> Wizfi310<serial::Serial<SampleIterator<T>>> {
-        let inspect = matches.occurrences_of("v") >= depth;
-        let it = serial::Serial::new(input, &matches, depth + 1);
-        Self {
-            it,
-            inspect,
-            data_to_send: 0,
-            data_to_receive: 0,
-            recv_header: None,
-            tx: String::new(),
-            rx: String::new(),
-        }
-    }
-}
-
-pub fn subcommand() -> App<'static, 'static> {
-    SubCommand::with_name("wizfi310").args(&serial::args())
-}
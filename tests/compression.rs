@@ -0,0 +1,57 @@
+use flate2::write::GzEncoder;
+use logic_trace_parser::compression::decompress;
+use std::io::{Cursor, Read, Write};
+
+fn payload() -> Vec<u8> {
+    (0..100_000u32).map(|i| (i * 7 / 13) as u8).collect()
+}
+
+fn round_trip(compressed: Vec<u8>) -> Vec<u8> {
+    let mut output = Vec::new();
+    decompress(Box::new(Cursor::new(compressed)))
+        .unwrap()
+        .read_to_end(&mut output)
+        .unwrap();
+    output
+}
+
+fn gzip(data: &[u8]) -> Vec<u8> {
+    let mut encoder = GzEncoder::new(Vec::new(), flate2::Compression::default());
+    encoder.write_all(data).unwrap();
+    encoder.finish().unwrap()
+}
+
+#[test]
+fn gzip_round_trip() {
+    let payload = payload();
+    assert_eq!(round_trip(gzip(&payload)), payload);
+    // concatenated members, as written by pigz or by appending to a file.
+    let (head, tail) = payload.split_at(12345);
+    assert_eq!(round_trip([gzip(head), gzip(tail)].concat()), payload);
+}
+
+#[test]
+fn zstd_round_trip() {
+    let payload = payload();
+    assert_eq!(
+        round_trip(zstd::encode_all(&payload[..], 3).unwrap()),
+        payload
+    );
+}
+
+#[test]
+fn uncompressed() {
+    let payload = payload();
+    assert_eq!(round_trip(payload.clone()), payload);
+    // shorter than the longest magic number.
+    assert_eq!(round_trip(vec![0x1f]), vec![0x1f]);
+    assert_eq!(round_trip(Vec::new()), Vec::<u8>::new());
+}
+
+#[test]
+fn xz_round_trip() {
+    let payload = payload();
+    let mut encoder = xz2::write::XzEncoder::new(Vec::new(), 6);
+    encoder.write_all(&payload).unwrap();
+    assert_eq!(round_trip(encoder.finish().unwrap()), payload);
+}
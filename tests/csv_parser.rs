@@ -0,0 +1,45 @@
+use logic_trace_parser::csv_parser::CsvParser;
+use logic_trace_parser::Error;
+
+fn parse(csv: &str) -> Vec<Result<(f64, u64), Error>> {
+    CsvParser::new(csv.as_bytes())
+        .map(|smp| smp.map(|smp| (smp.timestamp(), smp.sample())))
+        .collect()
+}
+
+#[test]
+fn channels() {
+    let samples = parse("Time [s],Channel 3,Channel 0\n0.0,1,0\n0.5,1,0\n1.0,0,1\n");
+    let samples: Vec<_> = samples.into_iter().map(Result::unwrap).collect();
+    assert_eq!(samples, vec![(0.0, 0b1000), (1.0, 0b0001)]);
+}
+
+#[test]
+fn non_finite_timestamps() {
+    for ts in &["nan", "NaN", "inf", "-inf", "infinity"] {
+        let samples = parse(&format!("Time [s],Channel 0\n0.0,1\n{},0\n", ts));
+        match samples[1] {
+            Err(Error::BadCsvRecord { line: 3, .. }) => {}
+            ref res => panic!("{}: {:?}", ts, res.as_ref().map_err(|e| e.to_string())),
+        }
+    }
+}
+
+#[test]
+fn backward_timestamps() {
+    // also checked on records that do not change the state.
+    for csv in &[
+        "Time [s],Channel 0\n1.0,1\n0.5,0\n",
+        "Time [s],Channel 0\n1.0,1\n0.5,1\n",
+    ] {
+        match parse(csv)[1] {
+            Err(Error::NonMonotonicTimestamp { previous, current }) => {
+                assert_eq!((previous, current), (1.0, 0.5))
+            }
+            ref res => panic!("{:?}", res.as_ref().map_err(|e| e.to_string())),
+        }
+    }
+    // negative and equal timestamps are fine.
+    let samples = parse("Time [s],Channel 0\n-1.0,1\n-1.0,0\n2.0,1\n");
+    assert!(samples.iter().all(Result::is_ok));
+}
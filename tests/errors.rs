@@ -0,0 +1,128 @@
+use logic_trace_parser::csv_parser::CsvParser;
+use logic_trace_parser::logicdata_parser::LogicDataParser;
+use logic_trace_parser::raw_parser::RawParser;
+use logic_trace_parser::sample::{Input, SampleIterator};
+use logic_trace_parser::spi::{SpiBuilder, SpiEvent};
+use logic_trace_parser::vcd_parser::VcdParser;
+use logic_trace_parser::{Error, Sample};
+use std::io::{self, Cursor, Read};
+
+/// Returns `data`, then fails on every read.
+struct Failing<'a>(&'a [u8]);
+impl Read for Failing<'_> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        match self.0.read(buf)? {
+            0 => Err(io::Error::other("device unplugged")),
+            n => Ok(n),
+        }
+    }
+}
+
+/// Reads up to 100 items, which is more than any of the inputs below holds.
+fn drain<T: Iterator<Item = Result<Sample, Error>>>(parser: T) -> Vec<Result<Sample, Error>> {
+    parser.take(100).collect()
+}
+
+fn ends_with_io_error(results: &[Result<Sample, Error>]) {
+    assert!(results.len() < 100, "the parser is not fused");
+    assert!(
+        matches!(results.last(), Some(Err(Error::Io(_)))),
+        "{:?}",
+        results.last()
+    );
+}
+
+#[test]
+fn parsers_stop_after_an_io_error() {
+    let vcd = b"$var wire 1 ! channel_0 $end\n$enddefinitions $end\n#0\n1!\n";
+    ends_with_io_error(&drain(VcdParser::new(Failing(vcd))));
+
+    let record = [0, 0, 0, 0, 0, 0, 0, 0, 1];
+    ends_with_io_error(&drain(LogicDataParser::new(Failing(&record), 1.)));
+
+    let csv = b"Time [s],Channel 0\n0.0,1\n";
+    ends_with_io_error(&drain(CsvParser::new(Failing(csv))));
+
+    ends_with_io_error(&drain(RawParser::new(Failing(&[0, 1, 1]), 1.)));
+}
+
+/// A mode 0 SPI transaction sending `mosi` on the channels named after their number, with `extra`
+/// inserted after the 4th bit.
+fn spi_vcd(mosi: u8, extra: &str) -> String {
+    let mut vcd = String::from(
+        "$timescale 1us $end
+$var wire 1 ! channel_0 $end
+$var wire 1 \" channel_2 $end
+$var wire 1 # channel_3 $end
+$enddefinitions $end
+#0
+1!
+0\"
+0#
+#1
+0!
+",
+    );
+    let mut ts = 2;
+    for bit in (0..8).rev() {
+        vcd += &format!(
+            "#{}\n{}\"\n#{}\n1#\n#{}\n0#\n",
+            ts,
+            (mosi >> bit) & 1,
+            ts + 1,
+            ts + 2
+        );
+        ts += 3;
+        if bit == 4 {
+            vcd += extra;
+        }
+    }
+    vcd += &format!("#{}\n1!\n", ts);
+    vcd
+}
+
+fn spi_events(input: Input<Cursor<Vec<u8>>>) -> Vec<Result<SpiEvent, Error>> {
+    SpiBuilder::new()
+        .into_spi(SampleIterator::from_input(input, false))
+        .unwrap()
+        .map(|res| res.map(|(_, ev)| ev))
+        .collect()
+}
+
+#[test]
+fn parse_errors_reach_the_decoder_and_decoding_goes_on() {
+    let vcd = spi_vcd(0xA5, "$bogus\n");
+    let events = spi_events(Input::Vcd(VcdParser::new(Cursor::new(vcd.into_bytes()))));
+    let error = events.iter().position(Result::is_err).expect("no error");
+    assert!(matches!(events[error], Err(Error::Io(_))));
+    // the word around the malformed command is still decoded.
+    assert!(events[error..]
+        .iter()
+        .any(|ev| matches!(ev, Ok(SpiEvent::Data { mosi: 0xA5, .. }))));
+    assert!(matches!(
+        events.last(),
+        Some(Ok(SpiEvent::ChipSelect(true)))
+    ));
+}
+
+#[test]
+fn truncated_record_reaches_the_decoder() {
+    let mut capture = Vec::new();
+    for (index, state) in [(0u64, 1u8), (1, 0), (2, 1 << 3), (3, 0)].iter() {
+        capture.extend_from_slice(&index.to_le_bytes());
+        capture.push(*state);
+    }
+    capture.extend_from_slice(&[4, 0, 0]);
+    let events = spi_events(Input::LogicData(LogicDataParser::new(
+        Cursor::new(capture),
+        1.,
+    )));
+    assert!(matches!(events[0], Ok(SpiEvent::ChipSelect(true))));
+    assert!(matches!(
+        events.last(),
+        Some(Err(Error::TruncatedRecord {
+            expected: 9,
+            read: 3
+        }))
+    ));
+}
@@ -0,0 +1,75 @@
+use logic_trace_parser::logic2_parser::Logic2Parser;
+
+const BEGIN: f64 = -2e-5;
+const CHANNELS: [u8; 3] = [0, 3, 7];
+
+/// State of the capture: a counter on the 3 channels, changing every `i % 4 + 1` microseconds.
+fn trace() -> Vec<(f64, u64)> {
+    let mut ts = BEGIN;
+    (0..30u64)
+        .map(|i| {
+            let state = CHANNELS
+                .iter()
+                .enumerate()
+                .filter(|&(bit, _)| (i >> bit) & 1 == 1)
+                .fold(0, |state, (_, &ch)| state | (1 << ch));
+            let change = (ts, state);
+            ts += (i % 4 + 1) as f64 * 1e-6;
+            change
+        })
+        .collect()
+}
+
+/// Builds the export of `channel`.
+fn export(trace: &[(f64, u64)], channel: u8) -> Vec<u8> {
+    let level = |state: u64| (state >> channel) & 1;
+    let transitions: Vec<f64> = trace
+        .windows(2)
+        .filter(|w| level(w[0].1) != level(w[1].1))
+        .map(|w| w[1].0)
+        .collect();
+    let end = trace.last().unwrap().0 + 1e-6;
+
+    let mut export = b"<SALEAE>".to_vec();
+    export.extend(&0i32.to_le_bytes());
+    export.extend(&0i32.to_le_bytes());
+    export.extend(&(level(trace[0].1) as u32).to_le_bytes());
+    export.extend(&BEGIN.to_le_bytes());
+    export.extend(&end.to_le_bytes());
+    export.extend(&(transitions.len() as u64).to_le_bytes());
+    for ts in transitions {
+        export.extend(&ts.to_le_bytes());
+    }
+    export
+}
+
+#[test]
+fn round_trip() {
+    let trace = trace();
+    let mut parser = Logic2Parser::new();
+    for &channel in CHANNELS.iter().rev() {
+        parser = parser
+            .channel(std::io::Cursor::new(export(&trace, channel)), channel)
+            .unwrap();
+    }
+    let samples: Vec<(f64, u64)> = parser
+        .map(|smp| {
+            let smp = smp.unwrap();
+            (smp.timestamp(), smp.sample())
+        })
+        .collect();
+    assert_eq!(samples, trace);
+}
+
+#[test]
+fn bad_header() {
+    let mut export = export(&trace(), 0);
+    // analog export.
+    export[12] = 1;
+    assert!(Logic2Parser::new()
+        .channel(std::io::Cursor::new(export), 0)
+        .is_err());
+    assert!(Logic2Parser::new()
+        .channel(std::io::Cursor::new(b"<SALEAE>".to_vec()), 0)
+        .is_err());
+}
@@ -0,0 +1,78 @@
+use logic_trace_parser::logicdata_parser::LogicDataParser;
+use std::io::{self, Read};
+
+/// Binary export of 16-channel `states`, the sample indexes go by 3.
+fn logicdata16(states: &[u16]) -> Vec<u8> {
+    let mut data = Vec::new();
+    for (index, state) in states.iter().enumerate() {
+        data.extend_from_slice(&(3 * index as u64).to_le_bytes());
+        data.extend_from_slice(&state.to_le_bytes());
+    }
+    data
+}
+
+fn samples<T: Read>(parser: LogicDataParser<T>) -> Vec<(u64, f64)> {
+    parser
+        .map(|smp| smp.map(|smp| (smp.sample(), smp.timestamp())).unwrap())
+        .collect()
+}
+
+#[test]
+fn sixteen_channels_round_trip() {
+    // each of the channels 8 to 15 set on its own, then along with the lower channels.
+    let states: Vec<u16> = (8..16)
+        .map(|channel| 1 << channel)
+        .chain((8..16).map(|channel| (1 << channel) | 0x00FF))
+        .collect();
+    let expected: Vec<(u64, f64)> = states
+        .iter()
+        .enumerate()
+        .map(|(index, &state)| (u64::from(state), index as f64))
+        .collect();
+    let capture = logicdata16(&states);
+    assert_eq!(
+        samples(LogicDataParser::new(&capture[..], 3.).channels(16)),
+        expected
+    );
+}
+
+/// Returns the data in chunks of 1, 4, 7 and 13 bytes.
+struct Chunks<'a> {
+    data: &'a [u8],
+    reads: usize,
+}
+impl Read for Chunks<'_> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let size = [1, 4, 7, 13][self.reads % 4].min(buf.len());
+        self.reads += 1;
+        let (chunk, rest) = self.data.split_at(size.min(self.data.len()));
+        buf[..chunk.len()].copy_from_slice(chunk);
+        self.data = rest;
+        Ok(chunk.len())
+    }
+}
+
+#[test]
+fn records_split_across_reads() {
+    let states: Vec<u16> = (0..100u16).map(|i| i.wrapping_mul(0x0301)).collect();
+    let wide = logicdata16(&states);
+    // the same records without the upper byte of the states.
+    let narrow: Vec<u8> = wide
+        .chunks(10)
+        .flat_map(|record| record[..9].to_vec())
+        .collect();
+    for &(channels, ref capture) in &[(8, narrow), (16, wide)] {
+        let expected = samples(LogicDataParser::new(&capture[..], 3.).channels(channels));
+        assert_eq!(expected.len(), 100);
+        let chunks = Chunks {
+            data: capture,
+            reads: 0,
+        };
+        assert_eq!(
+            samples(LogicDataParser::new(chunks, 3.).channels(channels)),
+            expected,
+            "{} channels",
+            channels
+        );
+    }
+}
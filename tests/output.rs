@@ -0,0 +1,315 @@
+use serde_json::Value;
+
+fn ltp(args: &[&str], stdin: &[u8]) -> std::process::Output {
+    use std::io::Write;
+    use std::process::{Command, Stdio};
+    let mut child = Command::new(env!("CARGO_BIN_EXE_ltp"))
+        .args(args)
+        .stdin(Stdio::piped())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped())
+        .spawn()
+        .unwrap();
+    child.stdin.take().unwrap().write_all(stdin).unwrap();
+    child.wait_with_output().unwrap()
+}
+
+/// Binary export of `states`, one sample per second.
+fn logicdata(states: &[u8]) -> Vec<u8> {
+    let mut data = Vec::new();
+    for (index, state) in states.iter().enumerate() {
+        data.extend_from_slice(&(index as u64).to_le_bytes());
+        data.push(*state);
+    }
+    data
+}
+
+const CS: u8 = 0;
+const MISO: u8 = 1;
+const MOSI: u8 = 2;
+const CLK: u8 = 3;
+
+/// Mode 0 transactions on the default channels, each one a list of `(mosi, miso)` bytes.
+fn spi_capture(transactions: &[&[(u8, u8)]]) -> Vec<u8> {
+    let mut states = vec![1 << CS];
+    for words in transactions {
+        states.push(0);
+        for &(mosi, miso) in words.iter() {
+            for bit in (0..8).rev() {
+                let data = ((mosi >> bit) & 1) << MOSI | ((miso >> bit) & 1) << MISO;
+                states.extend_from_slice(&[data, data | 1 << CLK, data]);
+            }
+        }
+        states.extend_from_slice(&[0, 1 << CS]);
+    }
+    logicdata(&states)
+}
+
+const TX: u8 = 0;
+const RX: u8 = 1;
+const BAUD: &str = "9600";
+/// 4 samples per bit.
+const FREQ: &str = "38400";
+
+/// 8N1 frames sent one after the other, each chunk on the `line` channel.
+fn serial_capture(chunks: &[(u8, &[u8])]) -> Vec<u8> {
+    let idle = 1 << TX | 1 << RX;
+    let mut bits = vec![idle; 4];
+    for &(line, bytes) in chunks {
+        for &byte in bytes {
+            let frame = (0..10).map(|bit| match bit {
+                0 => 0,
+                9 => 1,
+                bit => (byte >> (bit - 1)) & 1,
+            });
+            bits.extend(frame.map(|level| idle & !(1 << line) | level << line));
+            bits.extend_from_slice(&[idle; 2]);
+        }
+    }
+    let states: Vec<u8> = bits.iter().flat_map(|&s| vec![s; 4]).collect();
+    logicdata(&states)
+}
+
+/// Parses every line printed by `ltp` as a JSON object.
+fn jsonl(args: &[&str], stdin: &[u8]) -> Vec<Value> {
+    let output = ltp(args, stdin);
+    assert!(output.status.success(), "{:?}", output);
+    String::from_utf8(output.stdout)
+        .unwrap()
+        .lines()
+        .map(|line| {
+            let event: Value =
+                serde_json::from_str(line).unwrap_or_else(|e| panic!("{}: {}", e, line));
+            assert!(event["ts"].is_number(), "{}", line);
+            assert!(event["layer"].is_string(), "{}", line);
+            assert!(event["event"].is_string(), "{}", line);
+            event
+        })
+        .collect()
+}
+
+/// Events of `layer`, checking that only `layers` are printed.
+fn layer<'a>(events: &'a [Value], layers: &[&str], layer: &str) -> Vec<&'a Value> {
+    for name in layers {
+        assert!(
+            events.iter().any(|ev| ev["layer"] == *name),
+            "no {} event",
+            name
+        );
+    }
+    assert!(events
+        .iter()
+        .all(|ev| layers.iter().any(|name| ev["layer"] == *name)));
+    events.iter().filter(|ev| ev["layer"] == layer).collect()
+}
+
+#[test]
+fn spi_jsonl() {
+    let capture = spi_capture(&[&[(0x9F, 0x00), (0x00, 0xC2)]]);
+    let events = jsonl(&["--format", "jsonl", "-v", "spi"], &capture);
+    let spi = layer(&events, &["spi", "sample"], "spi");
+    // the chip select level is reported, it is active low.
+    assert_eq!(spi[0]["event"], "ChipSelect");
+    assert_eq!(spi[0]["cs"], true);
+    assert_eq!(spi[1]["cs"], false);
+    assert_eq!(spi[2]["event"], "Data");
+    assert_eq!(spi[2]["mosi"], "0x9f");
+    assert_eq!(spi[2]["miso"], "0x00");
+    assert_eq!(spi[3]["mosi"], "0x00");
+    assert_eq!(spi[3]["miso"], "0xc2");
+    assert_eq!(spi[4]["cs"], true);
+    let sample = layer(&events, &["spi", "sample"], "sample");
+    assert_eq!(sample[0]["event"], "Sample");
+    assert_eq!(sample[0]["channels"], 1 << CS);
+}
+
+#[test]
+fn spi_transactions_jsonl() {
+    // the second transaction has no clock edge to measure the clock frequency on.
+    let words = [(0x9F, 0x00), (0x00, 0xC2), (0x00, 0x20)];
+    let capture = spi_capture(&[&words, &[]]);
+    let events = jsonl(&["--format", "jsonl", "spi", "--transactions"], &capture);
+    let transactions = layer(&events, &["spi"], "spi");
+    assert_eq!(transactions.len(), 2);
+    assert_eq!(transactions[0]["event"], "Transaction");
+    assert_eq!(transactions[0]["mosi"], "9f0000");
+    assert_eq!(transactions[0]["miso"], "00c220");
+    assert_eq!(transactions[0]["clock_hz"], 1. / 3.);
+    assert_eq!(transactions[0]["end"], 75);
+    // back to back words follow each other by a clock period.
+    assert_eq!(transactions[0]["gaps"], serde_json::json!([3, 3]));
+    assert_eq!(transactions[1]["mosi"], "");
+    assert_eq!(transactions[1]["clock_hz"], Value::Null);
+    assert_eq!(transactions[1]["gaps"], serde_json::json!([]));
+}
+
+#[test]
+fn spif_jsonl() {
+    let read = [
+        (0x03, 0x00),
+        (0x12, 0x00),
+        (0x34, 0x00),
+        (0x56, 0x00),
+        (0x00, 0xA5),
+        (0x00, 0x5A),
+    ];
+    let capture = spi_capture(&[&[(0x06, 0x00)], &read]);
+    let events = jsonl(&["--format", "jsonl", "-v", "spif"], &capture);
+    let spif = layer(&events, &["spif", "spi"], "spif");
+    assert_eq!(spif[0]["event"], "WriteEnable");
+    assert_eq!(spif[1]["event"], "Read");
+    assert_eq!(spif[1]["addr"], "0x123456");
+    assert_eq!(spif[1]["len"], 2);
+    assert_eq!(spif[1]["data"], "a55a");
+}
+
+#[test]
+fn serial_jsonl() {
+    let capture = serial_capture(&[(RX, b"A"), (TX, b"\n")]);
+    let args = ["--format", "jsonl", "-v", "-f", FREQ, "serial", "-b", BAUD];
+    let events = jsonl(&args, &capture);
+    let serial = layer(&events, &["serial", "sample"], "serial");
+    assert_eq!(serial[0]["event"], "Cts");
+    assert_eq!(serial[0]["level"], true);
+    assert_eq!(serial[2]["event"], "Rx");
+    assert_eq!(serial[2]["data"], "0x41");
+    assert_eq!(serial[3]["event"], "Tx");
+    assert_eq!(serial[3]["data"], "0x0a");
+}
+
+#[test]
+fn wizfi310_jsonl() {
+    let capture = serial_capture(&[
+        (TX, b"AT+X=\"a\\b\"\x01\r"),
+        (RX, b"{0,192.168.1.2,8080,3}\"\\\n"),
+        (RX, b"OK\r\n"),
+    ]);
+    let args = [
+        "--format", "jsonl", "-v", "-f", FREQ, "wizfi310", "-b", BAUD,
+    ];
+    let events = jsonl(&args, &capture);
+    let wizfi310 = layer(&events, &["wizfi310", "serial"], "wizfi310");
+    assert_eq!(wizfi310[0]["event"], "Command");
+    assert_eq!(wizfi310[0]["data"], "AT+X=\"a\\b\"\u{1}\r");
+    assert_eq!(wizfi310[1]["event"], "Recv");
+    assert_eq!(wizfi310[1]["socket_id"], 0);
+    assert_eq!(wizfi310[1]["ip"], "192.168.1.2");
+    assert_eq!(wizfi310[1]["port"], 8080);
+    assert_eq!(wizfi310[1]["data"], "\"\\\n");
+    assert_eq!(wizfi310[2]["event"], "Resp");
+    assert_eq!(wizfi310[2]["data"], "OK\r\n");
+}
+
+/// Lines printed by `ltp`, the time column of the records is dropped.
+fn csv(args: &[&str], stdin: &[u8]) -> Vec<String> {
+    let output = ltp(args, stdin);
+    assert!(output.status.success(), "{:?}", output);
+    String::from_utf8(output.stdout)
+        .unwrap()
+        .lines()
+        .map(|line| line.split_once(',').unwrap().1.to_string())
+        .collect()
+}
+
+/// Checks that only the sample layer is printed.
+fn only_samples(lines: &[String]) {
+    assert_eq!(lines[0], "channels");
+    assert!(
+        lines[1..].iter().all(|l| l.parse::<u8>().is_ok()),
+        "{:?}",
+        lines
+    );
+}
+
+#[test]
+fn spi_csv() {
+    let capture = spi_capture(&[&[(0x9F, 0x00), (0x00, 0xC2)]]);
+    assert_eq!(
+        csv(&["--format", "csv", "spi"], &capture),
+        [
+            "cs,mosi,miso,bits,warning,margin",
+            "true,,,,,",
+            "false,,,,,",
+            ",0x9f,0x00,,,",
+            ",0x00,0xc2,,,",
+            "true,,,,,",
+        ]
+    );
+    only_samples(&csv(&["--format", "csv", "-v", "spi"], &capture));
+}
+
+#[test]
+fn spi_transactions_csv() {
+    let words = [(0x9F, 0x00), (0x00, 0xC2), (0x00, 0x20)];
+    let capture = spi_capture(&[&words, &[]]);
+    assert_eq!(
+        csv(&["--format", "csv", "spi", "--transactions"], &capture),
+        [
+            "end,clock_hz,mosi,miso,gaps",
+            "75,0.3333333333333333,9f0000,00c220,3 3",
+            "78,,,,",
+        ]
+    );
+}
+
+#[test]
+fn spif_csv() {
+    let read = [
+        (0x03, 0x00),
+        (0x00, 0x00),
+        (0x01, 0x00),
+        (0x00, 0x00),
+        (0x00, 0xA5),
+    ];
+    let capture = spi_capture(&[&[(0x06, 0x00)], &read]);
+    assert_eq!(
+        csv(&["--format", "csv", "spif"], &capture),
+        [
+            "command,addr,len,data",
+            "WriteEnable,,,",
+            "Read,0x000100,1,a5"
+        ]
+    );
+    let spi = csv(&["--format", "csv", "-v", "spif"], &capture);
+    assert_eq!(spi[0], "cs,mosi,miso,bits,warning,margin");
+    assert_eq!(spi[3], ",0x06,0x00,,,");
+    only_samples(&csv(&["--format", "csv", "-vv", "spif"], &capture));
+}
+
+#[test]
+fn serial_csv() {
+    let capture = serial_capture(&[(RX, b"A"), (TX, b"\n")]);
+    let args = ["--format", "csv", "-f", FREQ, "serial", "-b", BAUD];
+    assert_eq!(
+        csv(&args, &capture),
+        [
+            "dir,event,byte,error,level,duration",
+            "tx,Cts,,,true,",
+            "rx,Rts,,,true,",
+            "rx,Data,0x41,,,",
+            "tx,Data,0x0a,,,",
+        ]
+    );
+    let args = ["--format", "csv", "-v", "-f", FREQ, "serial", "-b", BAUD];
+    only_samples(&csv(&args, &capture));
+}
+
+#[test]
+fn wizfi310_csv() {
+    let capture = serial_capture(&[(TX, b"AT+X=\"a,b\"\r"), (RX, b"{1,10.0.0.1,80,3}a,b")]);
+    let args = ["--format", "csv", "-f", FREQ, "wizfi310", "-b", BAUD];
+    assert_eq!(
+        csv(&args, &capture),
+        [
+            "event,socket_id,ip,port,data",
+            "Command,,,,\"AT+X=\"\"a,b\"\"\r\"",
+            "Recv,1,10.0.0.1,80,\"a,b\"",
+        ]
+    );
+    let args = ["--format", "csv", "-v", "-f", FREQ, "wizfi310", "-b", BAUD];
+    let serial = csv(&args, &capture);
+    assert_eq!(serial[0], "dir,event,byte,error,level,duration");
+    assert!(serial[1..]
+        .iter()
+        .all(|l| l.starts_with("tx,") || l.starts_with("rx,")));
+}
@@ -0,0 +1,72 @@
+use logic_trace_parser::raw_parser::RawParser;
+use logic_trace_parser::Error;
+use std::io::{self, Read};
+
+const FREQ: f64 = 4e6;
+
+/// Dense samples: a counter on `mask`, each value held for `i % 3 + 1` samples.
+fn dense(mask: u16) -> Vec<u16> {
+    (0..50u16)
+        .flat_map(|i| std::iter::repeat_n(i.wrapping_mul(0x0F0F) & mask, usize::from(i % 3 + 1)))
+        .collect()
+}
+
+fn changes(dense: &[u16]) -> Vec<(f64, u64)> {
+    let mut changes: Vec<(f64, u64)> = Vec::new();
+    for (idx, &smp) in dense.iter().enumerate() {
+        if changes.last().map(|&(_, last)| last) != Some(u64::from(smp)) {
+            changes.push((idx as f64 / FREQ, u64::from(smp)));
+        }
+    }
+    changes
+}
+
+/// Reads at most 3 bytes at a time, splitting 16-bit samples across reads.
+struct Trickle<'a>(&'a [u8]);
+impl Read for Trickle<'_> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let len = buf.len().min(3);
+        self.0.read(&mut buf[..len])
+    }
+}
+
+fn parse<T: Read>(parser: RawParser<T>) -> Vec<Result<(f64, u64), Error>> {
+    parser
+        .map(|smp| smp.map(|smp| (smp.timestamp(), smp.sample())))
+        .collect()
+}
+
+#[test]
+fn round_trip_8_channels() {
+    let samples = dense(0xFF);
+    let bytes: Vec<u8> = samples.iter().map(|&smp| smp as u8).collect();
+    let parsed: Vec<_> = parse(RawParser::new(&bytes[..], FREQ))
+        .into_iter()
+        .map(Result::unwrap)
+        .collect();
+    assert_eq!(parsed, changes(&samples));
+}
+
+#[test]
+fn round_trip_16_channels() {
+    let samples = dense(0xFFFF);
+    let bytes: Vec<u8> = samples.iter().flat_map(|smp| smp.to_le_bytes()).collect();
+    let parser = RawParser::new(&bytes[..], FREQ).channels(16);
+    assert_eq!(parser.channel_count(), 16);
+    let parsed: Vec<_> = parse(parser).into_iter().map(Result::unwrap).collect();
+    assert_eq!(parsed, changes(&samples));
+
+    let parser = RawParser::new(Trickle(&bytes), FREQ).channels(16);
+    let parsed: Vec<_> = parse(parser).into_iter().map(Result::unwrap).collect();
+    assert_eq!(parsed, changes(&samples));
+
+    // a trailing half sample.
+    let parsed = parse(RawParser::new(&bytes[..bytes.len() - 1], FREQ).channels(16));
+    match parsed.last() {
+        Some(Err(Error::TruncatedRecord {
+            expected: 2,
+            read: 1,
+        })) => {}
+        _ => panic!("expected a truncated record"),
+    }
+}
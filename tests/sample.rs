@@ -0,0 +1,140 @@
+use logic_trace_parser::sample::InputFormat;
+use std::process::Command;
+
+/// Binary export records of `channels` channels with the given sample indexes.
+fn logicdata(indexes: &[i64], channels: u8) -> Vec<u8> {
+    indexes
+        .iter()
+        .flat_map(|&index| {
+            let mut record = index.to_le_bytes().to_vec();
+            record.resize(8 + usize::from(channels / 8), 0xA5);
+            record
+        })
+        .collect()
+}
+
+fn detect(head: &[u8], complete: bool) -> Option<(InputFormat, u8)> {
+    InputFormat::detect(head, complete, &[8, 16])
+}
+
+#[test]
+fn signatures() {
+    let text_formats: &[(&[u8], InputFormat)] = &[
+        (b"<SALEAE>\x00\x00\x00\x00", InputFormat::Logic2),
+        (b"PK\x03\x04\x14\x00", InputFormat::Sigrok),
+        (b"$date today $end\n", InputFormat::Vcd),
+        (b"$version 1 $end\n", InputFormat::Vcd),
+        (b"$timescale 1ns $end\n", InputFormat::Vcd),
+        (b"$comment x $end\n", InputFormat::Vcd),
+        (b"  \n$scope module top $end\n", InputFormat::Vcd),
+        (b"$var wire 1 ! clk $end\n", InputFormat::Vcd),
+        (b"Time [s], Channel 0, Channel 1\n0,1,0\n", InputFormat::Csv),
+        (b"time,D0\n", InputFormat::Csv),
+    ];
+    for &(head, format) in text_formats {
+        assert_eq!(
+            detect(head, true).map(|(format, _)| format),
+            Some(format),
+            "{:?}",
+            String::from_utf8_lossy(head)
+        );
+    }
+}
+
+#[test]
+fn logicdata_record_size() {
+    // 9 records of 10 bytes do not split in 9 bytes records.
+    let indexes: Vec<i64> = (0..9).map(|i| i * 3).collect();
+    assert_eq!(
+        detect(&logicdata(&indexes, 16), true),
+        Some((InputFormat::LogicData, 16))
+    );
+    // 8 channels are tried first.
+    assert_eq!(
+        detect(&logicdata(&indexes, 8), true),
+        Some((InputFormat::LogicData, 8))
+    );
+    // a truncated head may end with a partial record.
+    let mut head = logicdata(&indexes, 8);
+    head.truncate(head.len() - 4);
+    assert_eq!(detect(&head, false), Some((InputFormat::LogicData, 8)));
+    assert_eq!(detect(&head, true), None);
+    // only the given widths are tried.
+    assert_eq!(
+        InputFormat::detect(&logicdata(&[0, 10, 20, 30, 40], 16), true, &[16]),
+        Some((InputFormat::LogicData, 16))
+    );
+}
+
+#[test]
+fn unknown_formats() {
+    // sample indexes going backward.
+    assert_eq!(detect(&logicdata(&[0, 100, 50, 200], 8), true), None);
+    assert_eq!(detect(&logicdata(&[5, 4, 3, 2, 1], 16), true), None);
+    // shorter than a record.
+    assert_eq!(detect(b"\x00\x01\x02", true), None);
+    assert_eq!(detect(b"", true), None);
+    // a '$' line that is not a VCD keyword, text without a time column.
+    assert_eq!(detect(b"$foo bar baz\n", true), None);
+    assert_eq!(detect(b"hello world, not a trace\n", true), None);
+}
+
+#[test]
+fn unknown_input_format_error() {
+    let path = std::env::temp_dir().join(format!("ltp-unknown-{}.txt", std::process::id()));
+    std::fs::write(&path, "hello world, not a trace\n").unwrap();
+    let output = Command::new(env!("CARGO_BIN_EXE_ltp"))
+        .arg(&path)
+        .output()
+        .unwrap();
+    std::fs::remove_file(&path).unwrap();
+    assert!(!output.status.success());
+    assert!(String::from_utf8_lossy(&output.stderr).contains("Unable to detect the input format"));
+}
+
+/// Timestamps of the samples printed by `ltp args`.
+fn timestamps(args: &[&str], stdin: &[u8]) -> Vec<String> {
+    use std::io::Write;
+    use std::process::Stdio;
+    let mut child = Command::new(env!("CARGO_BIN_EXE_ltp"))
+        .args(args)
+        .stdin(Stdio::piped())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped())
+        .spawn()
+        .unwrap();
+    child.stdin.take().unwrap().write_all(stdin).unwrap();
+    let output = child.wait_with_output().unwrap();
+    assert!(output.status.success(), "{:?}", output);
+    String::from_utf8(output.stdout)
+        .unwrap()
+        .lines()
+        .map(|l| l.split(' ').next().unwrap().to_string())
+        .collect()
+}
+
+#[test]
+fn vcd_time_origin() {
+    let vcd = b"$timescale 1s $end
+$var wire 1 ! channel_0 $end
+$enddefinitions $end
+#5
+1!
+#8
+0!
+";
+    let origin = |origin| timestamps(&["--time-origin", origin], vcd);
+    assert_eq!(origin("zero"), ["5.000000", "8.000000"]);
+    assert_eq!(origin("first"), ["0.000000", "3.000000"]);
+    assert_eq!(origin("6.5"), ["-1.500000", "1.500000"]);
+}
+
+#[test]
+fn binary_time_origin() {
+    // sample indexes 10, 12 and 15 at 2 Hz.
+    let capture = logicdata(&[10, 12, 15], 8);
+    let origin = |origin| timestamps(&["-f", "2", "--time-origin", origin], &capture);
+    assert_eq!(origin("zero"), ["5.000000", "6.000000", "7.500000"]);
+    assert_eq!(origin("first"), ["0.000000", "1.000000", "2.500000"]);
+    assert_eq!(origin("-1"), ["6.000000", "7.000000", "8.500000"]);
+}
@@ -0,0 +1,472 @@
+use logic_trace_parser::serial::{Parity, SerialBuilder, SerialEvent};
+use logic_trace_parser::{Error, Sample};
+
+const TX: u8 = 0;
+const RX: u8 = 1;
+
+const BAUD: f64 = 9600.;
+/// Samples taken per bit time.
+const OVERSAMPLING: usize = 8;
+
+/// Levels of a frame as (length in bits, level) pairs, LSB first.
+fn frame(data: u16, data_bits: u32, parity: Option<bool>, stop_bits: f64) -> Vec<(f64, bool)> {
+    let mut levels = vec![(1., false)];
+    levels.extend((0..data_bits).map(|bit| (1., (data >> bit) & 1 == 1)));
+    levels.extend(parity.map(|p| (1., p)));
+    levels.push((stop_bits, true));
+    levels
+}
+
+/// Puts `levels` on `channel`, the other channels being held at `rest`.
+fn on(channel: u8, levels: &[(f64, bool)], rest: u64) -> Vec<(f64, u64)> {
+    levels
+        .iter()
+        .map(|&(bits, level)| (bits, rest | (u64::from(level) << channel)))
+        .collect()
+}
+
+/// Samples `segments` of (length in bits, state) from `start`, surrounded by an idle line.
+fn capture(start: f64, segments: &[(f64, u64)]) -> Vec<Result<Sample, Error>> {
+    capture_at(BAUD, start, segments)
+}
+
+fn capture_at(baud: f64, start: f64, segments: &[(f64, u64)]) -> Vec<Result<Sample, Error>> {
+    let idle = (1 << TX) | (1 << RX);
+    let mut samples = Vec::new();
+    let mut ts = start;
+    let step = 1. / baud / OVERSAMPLING as f64;
+    let segments = [(2., idle)]
+        .iter()
+        .chain(segments)
+        .chain(&[(4., idle)])
+        .copied()
+        .collect::<Vec<_>>();
+    for (bits, state) in segments {
+        for _ in 0..(bits * OVERSAMPLING as f64).round() as usize {
+            samples.push(Ok(Sample::new(state, ts)));
+            ts += step;
+        }
+    }
+    samples
+}
+
+/// Decodes `samples` and returns the events, flow control changes excepted.
+fn decode(samples: Vec<Result<Sample, Error>>, builder: SerialBuilder) -> Vec<(f64, String)> {
+    builder
+        .into_serial(samples.into_iter())
+        .unwrap()
+        .map(|ev| ev.unwrap())
+        .map(|(ts, ev)| (ts, format!("{:?}", ev)))
+        .filter(|(_, ev)| !ev.starts_with("Cts") && !ev.starts_with("Rts"))
+        .collect()
+}
+
+fn events(samples: Vec<Result<Sample, Error>>, builder: SerialBuilder) -> Vec<String> {
+    decode(samples, builder)
+        .into_iter()
+        .map(|(_, ev)| ev)
+        .collect()
+}
+
+fn rx_8n1(bytes: &[u8]) -> Vec<(f64, u64)> {
+    bytes
+        .iter()
+        .flat_map(|&b| on(RX, &frame(b.into(), 8, None, 1.), 1 << TX))
+        .collect()
+}
+
+#[test]
+fn parity() {
+    for &(parity, byte, bit, ok) in &[
+        // 'A' has an even number of ones.
+        (Parity::Even, b'A', false, true),
+        (Parity::Even, b'A', true, false),
+        (Parity::Even, b'C', true, true),
+        (Parity::Even, b'C', false, false),
+        (Parity::Odd, b'A', true, true),
+        (Parity::Odd, b'A', false, false),
+        (Parity::Odd, b'C', false, true),
+        (Parity::Odd, b'C', true, false),
+    ] {
+        let line = on(RX, &frame(byte.into(), 8, Some(bit), 1.), 1 << TX);
+        let builder = SerialBuilder::new().baud(BAUD).parity(parity);
+        let mut expected = vec![format!("Rx({:?})", byte as char)];
+        if !ok {
+            expected.insert(0, "RxError(Parity)".to_string());
+        }
+        assert_eq!(
+            events(capture(0., &line), builder),
+            expected,
+            "{:?} {:?} parity bit {}",
+            parity,
+            byte as char,
+            bit
+        );
+    }
+}
+
+#[test]
+fn parity_stream() {
+    let line: Vec<_> = b"Hi!"
+        .iter()
+        .flat_map(|&b| {
+            let even = b.count_ones() & 1 == 1;
+            on(RX, &frame(b.into(), 8, Some(even), 1.), 1 << TX)
+        })
+        .collect();
+    assert_eq!(
+        events(
+            capture(0., &line),
+            SerialBuilder::new().baud(BAUD).parity(Parity::Even)
+        ),
+        vec!["Rx('H')", "Rx('i')", "Rx('!')"]
+    );
+    assert_eq!(
+        events(
+            capture(0., &line),
+            SerialBuilder::new().baud(BAUD).parity(Parity::Odd)
+        )
+        .iter()
+        .filter(|ev| *ev == "RxError(Parity)")
+        .count(),
+        3
+    );
+}
+
+#[test]
+fn plain_8n1() {
+    assert_eq!(
+        events(capture(0., &rx_8n1(b"ok")), SerialBuilder::new().baud(BAUD)),
+        vec!["Rx('o')", "Rx('k')"]
+    );
+}
+
+fn detect(baud: f64) -> Result<f64, Error> {
+    SerialBuilder::new()
+        .into_serial(capture_at(baud, 0., &rx_8n1(b"U\x55\xAA0")).into_iter())
+        .map(|serial| serial.baud())
+}
+
+#[test]
+fn detect_baudrate() {
+    for &baud in &[1200., 9600., 115200., 921600., 1000000.] {
+        assert_eq!(detect(baud).unwrap(), baud);
+    }
+    // within 5% of both 921600 and 1M, but closer to 1M.
+    assert_eq!(detect(960000.).unwrap(), 1000000.);
+    assert_eq!(detect(9700.).unwrap(), 9600.);
+    // too far from any standard rate.
+    assert_eq!(detect(250000.).unwrap(), 250000.);
+
+    let decoded = SerialBuilder::new()
+        .into_serial(capture_at(57600., 0., &rx_8n1(b"auto")).into_iter())
+        .unwrap()
+        .filter_map(|ev| match ev.unwrap().1 {
+            SerialEvent::Rx(c) => Some(c as u8),
+            _ => None,
+        })
+        .collect::<Vec<_>>();
+    assert_eq!(decoded, b"auto");
+}
+
+#[test]
+fn undetectable_baudrate() {
+    let serial = SerialBuilder::new().into_serial(capture(0., &[]).into_iter());
+    match serial.map(|serial| serial.baud()) {
+        Err(Error::UndetectableBaudrate) => {}
+        Err(e) => panic!("unexpected error {}", e),
+        Ok(baud) => panic!("detected {} on an idle line", baud),
+    }
+}
+
+/// Returns the bytes received on Rx, and the other events.
+fn received(
+    samples: Vec<Result<Sample, Error>>,
+    builder: SerialBuilder,
+) -> (Vec<u16>, Vec<String>) {
+    let mut data = Vec::new();
+    let mut others = Vec::new();
+    for ev in builder.into_serial(samples.into_iter()).unwrap() {
+        match ev.unwrap().1 {
+            SerialEvent::Rx(v) => data.push(v),
+            SerialEvent::Rts(_) | SerialEvent::Cts(_) => {}
+            ev => others.push(format!("{:?}", ev)),
+        }
+    }
+    (data, others)
+}
+
+#[test]
+fn frame_formats() {
+    let words: [u16; 4] = [0x1A5, 0x05A, 0x1FF, 0x100];
+    for data_bits in 5..=9 {
+        for &stop_bits in &[1., 1.5, 2.] {
+            let words: Vec<u16> = words.iter().map(|w| w & ((1 << data_bits) - 1)).collect();
+            let line: Vec<_> = words
+                .iter()
+                .flat_map(|&w| on(RX, &frame(w, data_bits, None, stop_bits), 1 << TX))
+                .collect();
+            let builder = SerialBuilder::new()
+                .baud(BAUD)
+                .data_bits(data_bits)
+                .stop_bits(stop_bits);
+            assert_eq!(
+                received(capture(0., &line), builder),
+                (words, vec![]),
+                "{} data bits, {} stop bits",
+                data_bits,
+                stop_bits
+            );
+        }
+    }
+}
+
+#[test]
+fn nine_bit_words() {
+    let line = on(RX, &frame(0x1A5, 9, None, 2.), 1 << TX);
+    let builder = SerialBuilder::new().baud(BAUD).data_bits(9).stop_bits(2.);
+    assert_eq!(events(capture(0., &line), builder), vec!["Rx(0x1a5)"]);
+}
+
+#[test]
+fn low_second_stop_bit() {
+    for &stop_bits in &[1.5, 2.] {
+        let mut levels = frame(b'X'.into(), 8, None, 1.);
+        levels.push((stop_bits - 1., false));
+        let line = on(RX, &levels, 1 << TX);
+        let builder = SerialBuilder::new().baud(BAUD).stop_bits(stop_bits);
+        let (data, others) = received(capture(0., &line), builder);
+        assert!(!data.contains(&b'X'.into()), "{} stop bits", stop_bits);
+        assert_eq!(others[0], "RxError(Framing)", "{} stop bits", stop_bits);
+    }
+}
+
+#[test]
+fn inverted_line() {
+    let inverted = || {
+        let idle = (1 << TX) | (1 << RX);
+        capture(0., &rx_8n1(b"inv"))
+            .into_iter()
+            .map(move |smp| smp.map(|smp| Sample::new(smp.sample() ^ idle, smp.timestamp())))
+            .collect::<Vec<_>>()
+    };
+    assert_eq!(
+        events(inverted(), SerialBuilder::new().baud(BAUD).invert(true)),
+        vec!["Rx('i')", "Rx('n')", "Rx('v')"]
+    );
+    assert!(!events(inverted(), SerialBuilder::new().baud(BAUD))
+        .iter()
+        .any(|ev| ev == "Rx('i')"));
+}
+
+#[test]
+fn half_duplex() {
+    const DE: u8 = 2;
+    let mut line = on(RX, &frame(b'Q'.into(), 8, None, 1.), 1 << DE);
+    line.extend(on(RX, &[(1., true)], 0));
+    line.extend(on(RX, &frame(b'A'.into(), 8, None, 1.), 0));
+    line.extend(on(RX, &[(1., true)], 1 << DE));
+    line.extend(on(RX, &frame(b'R'.into(), 8, None, 1.), 1 << DE));
+    let builder = SerialBuilder::new().baud(BAUD).half_duplex(RX, DE);
+    assert_eq!(
+        events(capture(0., &line), builder),
+        vec!["Tx('Q')", "Rx('A')", "Tx('R')"]
+    );
+}
+
+/// Holds Rx low for `bits` bit times, then idle for 2 bit times.
+fn rx_break(bits: f64) -> Vec<(f64, u64)> {
+    on(RX, &[(bits, false), (2., true)], 1 << TX)
+}
+
+#[test]
+fn line_break() {
+    for &parity in &[Parity::None, Parity::Even, Parity::Odd] {
+        // 7 data bits and a parity bit, or 8 data bits.
+        let frame = |byte: u8| {
+            let (bits, parity) = match parity {
+                Parity::Even => (7, Some(byte.count_ones() & 1 == 1)),
+                Parity::Odd => (7, Some(byte.count_ones() & 1 == 0)),
+                _ => (8, None),
+            };
+            on(RX, &frame(byte.into(), bits, parity, 1.), 1 << TX)
+        };
+        let mut line = frame(b'a');
+        line.extend(rx_break(20.));
+        line.extend(frame(b'b'));
+        let builder = SerialBuilder::new().baud(BAUD).parity(parity);
+        let builder = if parity == Parity::None {
+            builder
+        } else {
+            builder.data_bits(7)
+        };
+        let decoded = decode(capture(0., &line), builder);
+        let kinds: Vec<_> = decoded.iter().map(|(_, ev)| &ev[..]).collect();
+        assert_eq!(kinds.len(), 3, "{:?}: {:?}", parity, kinds);
+        assert_eq!(kinds[0], "Rx('a')");
+        assert_eq!(kinds[2], "Rx('b')");
+        // the break starts after the first frame, lasts 20 bits and is reported at its end.
+        let end = (12. + 20.) / BAUD;
+        let duration = kinds[1]["RxBreak(".len()..kinds[1].len() - 1]
+            .parse::<f64>()
+            .unwrap();
+        assert!((decoded[1].0 - end).abs() < 1e-6, "{:?}", decoded);
+        assert!((duration - 20. / BAUD).abs() < 1e-6, "{:?}", decoded);
+    }
+}
+
+#[test]
+fn odd_parity_zero_byte() {
+    // a null byte with a low parity bit is a parity error, not a break.
+    let line = on(RX, &frame(0, 8, Some(false), 1.), 1 << TX);
+    assert_eq!(
+        events(
+            capture(0., &line),
+            SerialBuilder::new().baud(BAUD).parity(Parity::Odd)
+        ),
+        vec!["RxError(Parity)", "Rx('\\0')"]
+    );
+}
+
+#[test]
+fn break_at_end_of_capture() {
+    let mut line = rx_8n1(b"z");
+    line.extend(rx_break(30.));
+    let mut samples = capture(0., &line);
+    samples.truncate(samples.len() - 6 * OVERSAMPLING);
+    let events = events(samples, SerialBuilder::new().baud(BAUD));
+    assert_eq!(events[0], "Rx('z')");
+    assert!(events[1].starts_with("RxBreak("), "{:?}", events);
+}
+
+#[test]
+fn idle_line() {
+    let mut line = rx_8n1(b"ab");
+    line.extend(on(RX, &[(5., true)], 1 << TX));
+    line.extend(rx_8n1(b"c"));
+    let builder = SerialBuilder::new().baud(BAUD).idle_bits(3.);
+    let decoded = decode(capture(0., &line), builder);
+    let kinds: Vec<_> = decoded.iter().map(|(_, ev)| &ev[..]).collect();
+    assert_eq!(
+        kinds,
+        vec!["Rx('a')", "Rx('b')", "RxIdle", "Rx('c')", "RxIdle"]
+    );
+    // reported 3 bit times after the stop bit of 'b' was sampled.
+    let stop = (2. + 19.5) / BAUD;
+    assert!((decoded[2].0 - stop - 3. / BAUD).abs() < 1e-6);
+
+    // the line must stay idle for the whole idle time.
+    let mut line = rx_8n1(b"ab");
+    line.extend(on(RX, &[(2., true)], 1 << TX));
+    line.extend(rx_8n1(b"c"));
+    let builder = SerialBuilder::new().baud(BAUD).idle_bits(3.);
+    assert_eq!(
+        events(capture(0., &line), builder),
+        vec!["Rx('a')", "Rx('b')", "Rx('c')", "RxIdle"]
+    );
+}
+
+#[test]
+fn negative_timestamps() {
+    let decoded = decode(
+        capture(-1.0, &rx_8n1(b"-1")),
+        SerialBuilder::new().baud(BAUD),
+    );
+    let kinds: Vec<_> = decoded.iter().map(|(_, ev)| &ev[..]).collect();
+    assert_eq!(kinds, vec!["Rx('-')", "Rx('1')"]);
+    assert!(decoded.iter().all(|&(ts, _)| ts < 0.));
+}
+
+fn ltp(args: &[&str], stdin: &[u8]) -> std::process::Output {
+    use std::io::Write;
+    use std::process::{Command, Stdio};
+    let mut child = Command::new(env!("CARGO_BIN_EXE_ltp"))
+        .args(args)
+        .stdin(Stdio::piped())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped())
+        .spawn()
+        .unwrap();
+    child.stdin.take().unwrap().write_all(stdin).unwrap();
+    child.wait_with_output().unwrap()
+}
+
+/// Binary export of `samples`, taken at `BAUD * OVERSAMPLING`.
+fn logicdata(samples: &[Result<Sample, Error>]) -> Vec<u8> {
+    let mut data = Vec::new();
+    for (index, smp) in samples.iter().enumerate() {
+        data.extend_from_slice(&(index as u64).to_le_bytes());
+        data.push(smp.as_ref().unwrap().sample() as u8);
+    }
+    data
+}
+
+#[test]
+fn samples_interleaved_with_detected_baudrate() {
+    let mut segments = rx_8n1(b"A");
+    segments.push((20., (1 << TX) | (1 << RX)));
+    segments.extend(rx_8n1(b"B"));
+    let capture = logicdata(&capture(0., &segments));
+    let freq = (BAUD * OVERSAMPLING as f64).to_string();
+    let output = ltp(&["-v", "-f", &freq, "serial"], &capture);
+    assert!(output.status.success(), "{:?}", output);
+    let stdout = String::from_utf8(output.stdout).unwrap();
+    let lines: Vec<&str> = stdout.lines().collect();
+    let position = |event: &str| lines.iter().position(|l| l.ends_with(event)).unwrap();
+    // the samples read to detect the baudrate are printed along with the events they produce.
+    let (a, b) = (position("Rx('A')"), position("Rx('B')"));
+    assert!(a < b);
+    assert!(lines[a + 1..b].iter().filter(|l| !l.contains("Rx")).count() > 100);
+    assert!(lines[b + 1..].len() < 100);
+}
+
+#[test]
+fn invalid_configurations() {
+    let build =
+        |builder: SerialBuilder| builder.baud(BAUD).into_serial(Vec::new().into_iter()).err();
+    for &data_bits in &[0, 4, 10, 17] {
+        assert!(matches!(
+            build(SerialBuilder::new().data_bits(data_bits)),
+            Some(Error::Config(_))
+        ));
+    }
+    assert!(matches!(
+        build(SerialBuilder::new().rx(64)),
+        Some(Error::Config(_))
+    ));
+    assert!(matches!(
+        build(SerialBuilder::new().cts(255)),
+        Some(Error::Config(_))
+    ));
+    assert!(matches!(
+        build(SerialBuilder::new().half_duplex(2, 64)),
+        Some(Error::Config(_))
+    ));
+    assert!(matches!(
+        SerialBuilder::new()
+            .baud(0.)
+            .into_serial(Vec::new().into_iter())
+            .err(),
+        Some(Error::Config(_))
+    ));
+    assert!(build(SerialBuilder::new().tx(63).data_bits(9)).is_none());
+}
+
+#[test]
+fn frames_before_the_time_origin() {
+    let capture = logicdata(&capture(0., &rx_8n1(b"-1")));
+    let freq = (BAUD * OVERSAMPLING as f64).to_string();
+    let args = ["-f", &freq, "--time-origin", "0.01", "serial", "-b", "9600"];
+    let output = ltp(&args, &capture);
+    assert!(output.status.success(), "{:?}", output);
+    let stdout = String::from_utf8(output.stdout).unwrap();
+    let frames: Vec<(f64, &str)> = stdout
+        .lines()
+        .map(|l| l.split_once(' ').unwrap())
+        .filter(|(_, ev)| ev.starts_with("Rx"))
+        .map(|(ts, ev)| (ts.parse().unwrap(), ev))
+        .collect();
+    assert_eq!(frames.len(), 2, "{}", stdout);
+    assert_eq!((frames[0].1, frames[1].1), ("Rx('-')", "Rx('1')"));
+    assert!(frames.iter().all(|&(ts, _)| ts < 0.));
+}
@@ -0,0 +1,603 @@
+use logic_trace_parser::spi::{Mode, SpiBuilder, SpiEvent, SpiTransactions, SpiWarning};
+use logic_trace_parser::{Error, Sample};
+
+const CS: u8 = 0;
+const MISO: u8 = 1;
+const MOSI: u8 = 2;
+const CLK: u8 = 3;
+
+const MODES: [Mode; 4] = [Mode::Mode0, Mode::Mode1, Mode::Mode2, Mode::Mode3];
+
+/// Builds the samples of a transaction where data is shifted out on the edge preceding the
+/// sampling edge of `mode`, as a device would.
+fn transaction(mode: Mode, mosi: &[u8], miso: &[u8], cs: bool) -> Vec<Result<Sample, Error>> {
+    let mosi: Vec<u32> = mosi.iter().map(|&b| b.into()).collect();
+    let miso: Vec<u32> = miso.iter().map(|&b| b.into()).collect();
+    words(mode, &mosi, &miso, 8, false, cs)
+}
+
+/// Builds the samples of a transaction of `bits` bit words.
+fn words(
+    mode: Mode,
+    mosi: &[u32],
+    miso: &[u32],
+    bits: u8,
+    lsb_first: bool,
+    cs: bool,
+) -> Vec<Result<Sample, Error>> {
+    let idle = u64::from(mode.cpol()) << CLK;
+    let mut samples = Vec::new();
+    let mut push = |state: u64| {
+        let ts = samples.len() as f64 * 1e-6;
+        samples.push(Ok(Sample::new(state, ts)));
+    };
+    let cs_inactive = if cs { 1 << CS } else { 0 };
+    push(cs_inactive | idle);
+
+    let order: Vec<u8> = if lsb_first {
+        (0..bits).collect()
+    } else {
+        (0..bits).rev().collect()
+    };
+    let bits: Vec<u64> = mosi
+        .iter()
+        .zip(miso)
+        .flat_map(|(&mosi, &miso)| {
+            order.iter().map(move |&bit| {
+                (u64::from((mosi >> bit) & 1) << MOSI) | (u64::from((miso >> bit) & 1) << MISO)
+            })
+        })
+        .collect();
+    let active = idle;
+    let driving = idle ^ (1 << CLK);
+    // data changes a sample after the shifting edge.
+    let mut data = if mode.cpha() { 0 } else { bits[0] };
+    push(active | data);
+    for (i, &bit) in bits.iter().enumerate() {
+        if mode.cpha() {
+            // shifted on the leading edge, sampled on the trailing one.
+            push(driving | data);
+            data = bit;
+            push(driving | data);
+            push(active | data);
+        } else {
+            // sampled on the leading edge, shifted on the trailing one.
+            push(driving | data);
+            push(active | data);
+            data = bits.get(i + 1).copied().unwrap_or(bit);
+            push(active | data);
+        }
+    }
+    push(cs_inactive | idle);
+    samples
+}
+
+fn decode(samples: Vec<Result<Sample, Error>>, builder: SpiBuilder) -> Vec<(u32, u32)> {
+    builder
+        .into_spi(samples.into_iter())
+        .unwrap()
+        .filter_map(|ev| match ev.unwrap() {
+            (_, SpiEvent::Data { mosi, miso }) => Some((mosi, miso)),
+            _ => None,
+        })
+        .collect()
+}
+
+#[test]
+fn mode_matrix() {
+    let expected = vec![(0x9F, 0x00), (0xA5, 0x3C), (0x01, 0x80)];
+    let (mosi, miso): (Vec<u8>, Vec<u8>) = expected
+        .iter()
+        .map(|&(mosi, miso): &(u32, u32)| (mosi as u8, miso as u8))
+        .unzip();
+
+    for &trace_mode in &MODES {
+        for &decoder_mode in &MODES {
+            let samples = transaction(trace_mode, &mosi, &miso, true);
+            let data = decode(samples, SpiBuilder::new().mode(decoder_mode));
+            // modes 0 and 3 (1 and 2) only differ by the idle level of the clock, they sample
+            // on the same edge. Sampling on the other edge reads the data of CPHA 0 traces, held
+            // over the whole clock period, but reads CPHA 1 traces a bit late.
+            let same_edge =
+                trace_mode.cpol() ^ trace_mode.cpha() == decoder_mode.cpol() ^ decoder_mode.cpha();
+            assert_eq!(
+                data == expected,
+                same_edge || !trace_mode.cpha(),
+                "trace in {:?} decoded in {:?}: {:x?}",
+                trace_mode,
+                decoder_mode,
+                data
+            );
+        }
+    }
+}
+
+#[test]
+fn mode_from_cpol_cpha() {
+    for &mode in &MODES {
+        assert_eq!(Mode::from_cpol_cpha(mode.cpol(), mode.cpha()), mode);
+    }
+    assert!(!Mode::Mode1.cpol() && Mode::Mode1.cpha());
+    assert!(Mode::Mode2.cpol() && !Mode::Mode2.cpha());
+}
+
+#[test]
+fn cs_less() {
+    let mut samples = transaction(Mode::Mode0, &[0x12], &[0x34], false);
+    let end = samples.len() as f64 * 1e-6;
+    samples.extend(
+        transaction(Mode::Mode0, &[0x56], &[0x78], false)
+            .into_iter()
+            .map(|smp| smp.map(|smp| Sample::new(smp.sample(), smp.timestamp() + end + 1e-3))),
+    );
+
+    let events: Vec<SpiEvent> = SpiBuilder::new()
+        .idle_timeout(1e-4)
+        .into_spi(samples.into_iter())
+        .unwrap()
+        .map(|ev| ev.unwrap().1)
+        .collect();
+    let cs: Vec<bool> = events
+        .iter()
+        .filter_map(|ev| match ev {
+            SpiEvent::ChipSelect(cs) => Some(*cs),
+            _ => None,
+        })
+        .collect();
+    assert_eq!(cs, vec![false, true, false, true]);
+    assert!(matches!(
+        events[1],
+        SpiEvent::Data {
+            mosi: 0x12,
+            miso: 0x34
+        }
+    ));
+}
+
+#[test]
+fn glitch_filter() {
+    let expected = vec![(0x9F, 0x00), (0xA5, 0x3C)];
+    // every rising edge of the clock rings.
+    let samples: Vec<Result<Sample, Error>> =
+        transaction(Mode::Mode0, &[0x9F, 0xA5], &[0, 0x3C], true)
+            .into_iter()
+            .flat_map(|smp| {
+                let smp = smp.unwrap();
+                let (sample, ts) = (smp.sample(), smp.timestamp());
+                let mut ringing = vec![Ok(smp)];
+                if sample & (1 << CLK) != 0 {
+                    ringing.push(Ok(Sample::new(sample & !(1 << CLK), ts + 1e-8)));
+                    ringing.push(Ok(Sample::new(sample, ts + 2e-8)));
+                }
+                ringing
+            })
+            .collect();
+
+    let glitches = decode(samples.iter().map(copy).collect(), SpiBuilder::new());
+    assert_ne!(glitches, expected);
+    let filtered = decode(samples, SpiBuilder::new().min_pulse_width(1e-7));
+    assert_eq!(filtered, expected);
+}
+
+fn copy(smp: &Result<Sample, Error>) -> Result<Sample, Error> {
+    let smp = smp.as_ref().unwrap();
+    Ok(Sample::new(smp.sample(), smp.timestamp()))
+}
+
+#[test]
+fn three_wire() {
+    // the device answers 0x5A on the same line after an 8-bit command.
+    let samples = transaction(Mode::Mode0, &[0x0F, 0x5A], &[0, 0], true);
+    let data = decode(samples, SpiBuilder::new().sdio(MOSI, 8));
+    assert_eq!(data, vec![(0x0F, 0), (0, 0x5A)]);
+}
+
+#[test]
+fn transactions() {
+    let mut samples = transaction(Mode::Mode0, &[0x9F, 0, 0], &[0, 0xC2, 0x20], true);
+    let end = samples.len() as f64 * 1e-6;
+    samples.extend(
+        transaction(Mode::Mode0, &[0x06], &[0], true)
+            .into_iter()
+            .map(|smp| smp.map(|smp| Sample::new(smp.sample(), smp.timestamp() + end))),
+    );
+
+    let transactions: Vec<_> = SpiTransactions::from_spi(
+        SpiBuilder::new().into_spi(samples.into_iter()).unwrap(),
+        false,
+    )
+    .map(|res| res.unwrap().1)
+    .collect();
+    assert_eq!(transactions.len(), 2);
+    assert_eq!(transactions[0].mosi, vec![0x9F, 0, 0]);
+    assert_eq!(transactions[0].miso, vec![0, 0xC2, 0x20]);
+    assert_eq!(transactions[1].mosi, vec![0x06]);
+    // a clock period is 3 samples, words are sent back to back.
+    let clock_hz = transactions[0].clock_hz.unwrap();
+    assert!((clock_hz - 1e6 / 3.).abs() < 1.);
+    assert_eq!(transactions[0].gaps.len(), 2);
+    assert!(transactions[0]
+        .gaps
+        .iter()
+        .all(|gap| (gap - 3e-6).abs() < 1e-9));
+}
+
+fn events(samples: Vec<Result<Sample, Error>>, builder: SpiBuilder) -> Vec<SpiEvent> {
+    builder
+        .into_spi(samples.into_iter())
+        .unwrap()
+        .map(|ev| ev.unwrap().1)
+        .filter(|ev| !matches!(ev, SpiEvent::ChipSelect(_)))
+        .collect()
+}
+
+#[test]
+fn word_sizes() {
+    for &bits in &[4u8, 9, 12, 16, 24, 32] {
+        let mask = u32::MAX >> (32 - bits);
+        let mosi: Vec<u32> = [0x8000_0001, 0x1234_5678, 0xFFFF_FFFF]
+            .iter()
+            .map(|w| w & mask)
+            .collect();
+        let miso: Vec<u32> = [0x5A5A_5A5A, 0, 0xC0DE_CAFE]
+            .iter()
+            .map(|w| w & mask)
+            .collect();
+        for &lsb_first in &[false, true] {
+            let samples = words(Mode::Mode0, &mosi, &miso, bits, lsb_first, true);
+            let builder = SpiBuilder::new().bits(bits).lsb_first(lsb_first);
+            let expected: Vec<_> = mosi.iter().cloned().zip(miso.iter().cloned()).collect();
+            assert_eq!(
+                decode(samples, builder),
+                expected,
+                "{} bits, lsb first: {}",
+                bits,
+                lsb_first
+            );
+        }
+    }
+}
+
+#[test]
+fn lsb_first() {
+    let samples = transaction(Mode::Mode0, &[0x01, 0xA0], &[0x80, 0x0F], true);
+    assert_eq!(
+        decode(samples, SpiBuilder::new().lsb_first(true)),
+        vec![(0x80, 0x01), (0x05, 0xF0)]
+    );
+}
+
+#[test]
+fn partial_word() {
+    // a 12-bit word decoded as bytes leaves 4 bits when the chip select is released.
+    let samples = words(Mode::Mode0, &[0xABC], &[0x123], 12, false, true);
+    let decoded = events(samples, SpiBuilder::new());
+    assert!(
+        matches!(
+            decoded[..],
+            [
+                SpiEvent::Data {
+                    mosi: 0xAB,
+                    miso: 0x12
+                },
+                SpiEvent::Partial {
+                    mosi: 0xC,
+                    miso: 0x3,
+                    bits: 4
+                }
+            ]
+        ),
+        "{:x?}",
+        decoded
+    );
+
+    let samples = words(Mode::Mode0, &[0xABC], &[0x123], 12, true, true);
+    let decoded = events(samples, SpiBuilder::new().lsb_first(true));
+    assert!(
+        matches!(
+            decoded[..],
+            [
+                SpiEvent::Data {
+                    mosi: 0xBC,
+                    miso: 0x23
+                },
+                SpiEvent::Partial {
+                    mosi: 0xA,
+                    miso: 0x1,
+                    bits: 4
+                }
+            ]
+        ),
+        "{:x?}",
+        decoded
+    );
+
+    // no partial word on word boundaries.
+    let samples = words(Mode::Mode0, &[0xABC], &[0x123], 12, false, true);
+    assert!(!events(samples, SpiBuilder::new().bits(12))
+        .iter()
+        .any(|ev| matches!(ev, SpiEvent::Partial { .. })));
+}
+
+fn warnings(samples: Vec<Result<Sample, Error>>, builder: SpiBuilder) -> Vec<SpiWarning> {
+    builder
+        .into_spi(samples.into_iter())
+        .unwrap()
+        .filter_map(|ev| match ev.unwrap().1 {
+            SpiEvent::Warning(warning) => Some(warning),
+            _ => None,
+        })
+        .collect()
+}
+
+#[test]
+fn setup_warnings() {
+    // data changes 1 µs before the sampling edge, but at the byte boundary where it is unchanged.
+    let samples = || transaction(Mode::Mode0, &[0xAA, 0x55], &[0x55, 0xAA], true);
+    assert!(warnings(samples(), SpiBuilder::new().setup_time(0.5e-6)).is_empty());
+    let warnings = warnings(samples(), SpiBuilder::new().setup_time(1.5e-6));
+    assert_eq!(warnings.len(), 15);
+    assert!(
+        warnings
+            .iter()
+            .all(|w| matches!(*w, SpiWarning::Setup { margin } if (margin - 1e-6).abs() < 1e-9)),
+        "{:?}",
+        warnings
+    );
+}
+
+#[test]
+fn hold_warnings() {
+    // data changes 2 µs after the sampling edge, when it changes.
+    let samples = || transaction(Mode::Mode0, &[0xAA, 0x55], &[0x55, 0xAA], true);
+    assert!(warnings(samples(), SpiBuilder::new().hold_time(1.5e-6)).is_empty());
+    let warnings = warnings(samples(), SpiBuilder::new().hold_time(2.5e-6));
+    assert_eq!(warnings.len(), 14);
+    assert!(
+        warnings
+            .iter()
+            .all(|w| matches!(*w, SpiWarning::Hold { margin } if (margin - 2e-6).abs() < 1e-9)),
+        "{:?}",
+        warnings
+    );
+}
+
+#[test]
+fn clock_idle_level_warning() {
+    // a mode 2 device, its clock idles high, decoded in mode 0.
+    let samples = || transaction(Mode::Mode2, &[0x9F], &[0], true);
+    assert!(warnings(
+        samples(),
+        SpiBuilder::new().mode(Mode::Mode2).bus_warnings(true)
+    )
+    .is_empty());
+    assert!(warnings(samples(), SpiBuilder::new()).is_empty());
+    assert_eq!(
+        warnings(samples(), SpiBuilder::new().bus_warnings(true)),
+        vec![SpiWarning::ClockIdleLevel]
+    );
+}
+
+#[test]
+fn clock_without_cs_warning() {
+    let samples = || {
+        let mut samples = transaction(Mode::Mode0, &[0x9F], &[0], true);
+        let end = samples.len() as f64 * 1e-6;
+        // the clock keeps running once the chip select is released.
+        for i in 0..8 {
+            let clk = u64::from(i & 1 == 0) << CLK;
+            samples.push(Ok(Sample::new((1 << CS) | clk, end + f64::from(i) * 1e-6)));
+        }
+        samples
+    };
+    assert!(warnings(samples(), SpiBuilder::new()).is_empty());
+    assert_eq!(
+        warnings(samples(), SpiBuilder::new().bus_warnings(true)),
+        vec![SpiWarning::ClockWithoutCs]
+    );
+}
+
+/// A raw capture of a 0x9F command sampled at 1 MHz, the clock ringing a sample after each
+/// rising edge.
+fn ringing_raw_capture() -> Vec<u8> {
+    let mut samples = vec![1 << CS; 4];
+    for bit in (0..8).rev() {
+        let mosi = ((0x9F >> bit) & 1) << MOSI;
+        samples.extend(&[mosi; 4]);
+        let clk = mosi | (1 << CLK);
+        samples.extend(&[clk, mosi, clk, clk, clk, clk]);
+    }
+    samples.extend(&[1 << CS; 4]);
+    samples
+}
+
+fn ltp(args: &[&str], stdin: &[u8]) -> std::process::Output {
+    use std::io::Write;
+    use std::process::{Command, Stdio};
+    let mut child = Command::new(env!("CARGO_BIN_EXE_ltp"))
+        .args(args)
+        .stdin(Stdio::piped())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped())
+        .spawn()
+        .unwrap();
+    child.stdin.take().unwrap().write_all(stdin).unwrap();
+    child.wait_with_output().unwrap()
+}
+
+#[test]
+fn min_pulse_in_ticks() {
+    let capture = ringing_raw_capture();
+    let decoded = |args: &[&str]| {
+        let output = ltp(args, &capture);
+        assert!(output.status.success(), "{:?}", output);
+        String::from_utf8(output.stdout).unwrap()
+    };
+    let command = "Data { mosi: 159, miso: 0 }";
+    assert!(!decoded(&["--raw", "-f", "1e6", "spi"]).contains(command));
+    // 2 ticks at 1 MHz.
+    assert!(decoded(&["--raw", "-f", "1e6", "spi", "--min-pulse", "2t"]).contains(command));
+    assert!(decoded(&["--raw", "-f", "1e6", "spi", "--min-pulse", "2e-6"]).contains(command));
+    // ticks follow the sample rate, 2 µs are 20 ticks at 10 MHz and filter the whole clock.
+    assert!(decoded(&["--raw", "-f", "1e7", "spi", "--min-pulse", "2t"]).contains(command));
+    assert!(!decoded(&["--raw", "-f", "1e7", "spi", "--min-pulse", "2e-6"]).contains(command));
+}
+
+#[test]
+fn min_pulse_in_ticks_needs_a_sample_rate() {
+    let vcd = b"$timescale 1us $end
+$var wire 1 ! channel_3 $end
+$enddefinitions $end
+#0
+0!
+#1
+1!
+";
+    let output = ltp(&["--vcd", "spi", "--min-pulse", "2t"], vcd);
+    assert!(!output.status.success());
+    assert!(String::from_utf8_lossy(&output.stderr).contains("min-pulse"));
+}
+
+#[test]
+fn invalid_configurations() {
+    let build = |builder: SpiBuilder| builder.into_spi(Vec::new().into_iter()).err();
+    for &bits in &[0u8, 3, 33] {
+        assert!(matches!(
+            build(SpiBuilder::new().bits(bits)),
+            Some(Error::Config(_))
+        ));
+    }
+    assert!(matches!(
+        build(SpiBuilder::new().clk(64)),
+        Some(Error::Config(_))
+    ));
+    assert!(matches!(
+        build(SpiBuilder::new().qspi(4, 64)),
+        Some(Error::Config(_))
+    ));
+    assert!(matches!(
+        build(SpiBuilder::new().sdio(200, 8)),
+        Some(Error::Config(_))
+    ));
+    assert!(build(SpiBuilder::new().clk(63).bits(32)).is_none());
+}
+
+#[test]
+fn sixteen_channels() {
+    // the bus is on channels 12 to 15, channels 0 to 11 hold a constant pattern.
+    let mut capture = Vec::new();
+    let samples = transaction(Mode::Mode0, &[0x9F, 0x5A], &[0x00, 0xC3], true);
+    for (index, smp) in samples.iter().enumerate() {
+        let state = (smp.as_ref().unwrap().sample() as u16) << 12 | 0x0A5F;
+        capture.extend_from_slice(&(index as u64).to_le_bytes());
+        capture.extend_from_slice(&state.to_le_bytes());
+    }
+    let args = [
+        "--channels",
+        "16",
+        "spi",
+        "--cs",
+        "12",
+        "--miso",
+        "13",
+        "--mosi",
+        "14",
+        "--clk",
+        "15",
+    ];
+    let output = ltp(&args, &capture);
+    assert!(output.status.success(), "{:?}", output);
+    let stdout = String::from_utf8(output.stdout).unwrap();
+    assert!(stdout.contains("Data { mosi: 159, miso: 0 }"), "{}", stdout);
+    assert!(
+        stdout.contains("Data { mosi: 90, miso: 195 }"),
+        "{}",
+        stdout
+    );
+}
+
+#[test]
+fn channel_out_of_the_input() {
+    let capture = [0u8; 9];
+    let output = ltp(&["--channels", "8", "spi", "--cs", "16"], &capture);
+    assert!(!output.status.success());
+    assert!(
+        String::from_utf8_lossy(&output.stderr)
+            .contains("Invalid channel 16 for --cs: the input only has 8 channels"),
+        "{:?}",
+        output
+    );
+}
+
+/// A mode 0 transaction dumped by a Verilator model: the bus is in `TOP.top.spi`, miso is bit 3
+/// of the `io` vector and a second bus in `TOP.top.other_spi` ends with the same names.
+fn verilator_vcd(mosi: u8, miso: u8) -> String {
+    let mut vcd = String::from(
+        "$version Generated by VerilatedVcd $end
+$timescale 1ps $end
+ $scope module TOP $end
+  $scope module top $end
+   $scope module other_spi $end
+    $var wire  1 $ cs_n $end
+    $var wire  1 % sck $end
+   $upscope $end
+   $scope module spi $end
+    $var wire  1 # cs_n $end
+    $var wire  4 ( io [3:0] $end
+    $var wire  1 ' mosi $end
+    $var wire  1 & sck $end
+   $upscope $end
+  $upscope $end
+ $upscope $end
+$enddefinitions $end
+
+
+#0
+1#
+b0000 (
+0'
+0&
+1$
+0%
+#1000
+0#
+",
+    );
+    let mut ts = 2000;
+    for bit in (0..8).rev() {
+        vcd += &format!(
+            "#{}\n{}'\nb{:04b} (\n#{}\n1&\n1%\n#{}\n0&\n0%\n",
+            ts,
+            (mosi >> bit) & 1,
+            ((miso >> bit) & 1) << 3,
+            ts + 1000,
+            ts + 2000
+        );
+        ts += 3000;
+    }
+    vcd += &format!("#{}\n1#\n", ts);
+    vcd
+}
+
+#[test]
+fn verilator_signal_map() {
+    let vcd = verilator_vcd(0xA5, 0x3C);
+    // roles follow the channel arguments: clk is on channel 7.
+    let map = "cs=spi.cs_n,clk=top.spi.sck,mosi=TOP.top.spi.mosi,miso=spi.io[3]";
+    let output = ltp(&["--map", map, "spi", "--clk", "7"], vcd.as_bytes());
+    assert!(output.status.success(), "{:?}", output);
+    let stdout = String::from_utf8(output.stdout).unwrap();
+    let events: Vec<&str> = stdout
+        .lines()
+        .map(|l| l.split_once(' ').unwrap().1)
+        .collect();
+    assert_eq!(
+        events,
+        [
+            "ChipSelect(true)",
+            "ChipSelect(false)",
+            "Data { mosi: 165, miso: 60 }",
+            "ChipSelect(true)"
+        ]
+    );
+}
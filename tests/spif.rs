@@ -0,0 +1,176 @@
+use logic_trace_parser::spi::{SpiBuilder, SpiTransactions};
+use logic_trace_parser::spif::{Command, Spif};
+use logic_trace_parser::{Error, Sample};
+
+const CS: u8 = 0;
+const CLK: u8 = 3;
+/// Channels of IO0 (mosi) to IO3.
+const IO: [u8; 4] = [2, 1, 4, 5];
+
+/// Values of the IO lanes on each clock cycle shifting `bytes` out on `lanes` lanes.
+fn cycles(lanes: u8, bytes: &[u8]) -> Vec<u8> {
+    let mask = (1 << lanes) - 1;
+    bytes
+        .iter()
+        .flat_map(|&b| (0..8 / lanes).rev().map(move |i| (b >> (i * lanes)) & mask))
+        .collect()
+}
+
+/// Builds a mode 0 transaction clocking `cycles` out.
+fn transaction(cycles: &[u8]) -> Vec<Result<Sample, Error>> {
+    let mut samples = Vec::new();
+    let mut push = |state: u64| {
+        let ts = samples.len() as f64 * 1e-7;
+        samples.push(Ok(Sample::new(state, ts)));
+    };
+    push(1 << CS);
+    push(0);
+    for &io in cycles {
+        let data = IO.iter().enumerate().fold(0, |state, (lane, &ch)| {
+            state | (u64::from((io >> lane) & 1) << ch)
+        });
+        push(data);
+        push(data | (1 << CLK));
+        push(data);
+    }
+    push(1 << CS);
+    samples
+}
+
+/// Decodes the reads of a transaction.
+fn reads(cycles: &[u8], builder: SpiBuilder) -> Vec<(u32, Vec<u8>)> {
+    let samples = transaction(cycles);
+    Spif::from_spi(builder.into_spi(samples.into_iter()).unwrap(), false)
+        .filter_map(|res| match res.unwrap() {
+            (_, Command::Read(read)) => Some((read.addr, read.data)),
+            _ => None,
+        })
+        .collect()
+}
+
+const ADDR: [u8; 3] = [0x12, 0x34, 0x56];
+const DATA: [u8; 4] = [0xDE, 0xAD, 0xBE, 0xEF];
+
+fn quad() -> SpiBuilder {
+    SpiBuilder::new().qspi(IO[2], IO[3])
+}
+
+#[test]
+fn quad_output_read() {
+    // 1-1-4: command and address on IO0, 8 dummy cycles, data on 4 lanes.
+    let mut trace = cycles(1, &[0x6B]);
+    trace.extend(cycles(1, &ADDR));
+    trace.extend(vec![0; 8]);
+    trace.extend(cycles(4, &DATA));
+    assert_eq!(reads(&trace, quad()), vec![(0x123456, DATA.to_vec())]);
+}
+
+#[test]
+fn quad_io_read() {
+    // 1-4-4: command on IO0, address on 4 lanes, 6 dummy cycles (mode bits included).
+    let mut trace = cycles(1, &[0xEB]);
+    trace.extend(cycles(4, &ADDR));
+    trace.extend(vec![0xF, 0xF, 0, 0, 0, 0]);
+    trace.extend(cycles(4, &DATA));
+    assert_eq!(reads(&trace, quad()), vec![(0x123456, DATA.to_vec())]);
+
+    // the dummy cycles can be overridden.
+    let mut trace = cycles(1, &[0xEB]);
+    trace.extend(cycles(4, &ADDR));
+    trace.extend(vec![0; 10]);
+    trace.extend(cycles(4, &DATA));
+    assert_eq!(
+        reads(&trace, quad().dummy_cycles(10)),
+        vec![(0x123456, DATA.to_vec())]
+    );
+}
+
+#[test]
+fn qpi_read() {
+    // 4-4-4: the command is sent on 4 lanes too.
+    let mut trace = cycles(4, &[0xEB]);
+    trace.extend(cycles(4, &ADDR));
+    trace.extend(vec![0; 6]);
+    trace.extend(cycles(4, &DATA));
+    assert_eq!(
+        reads(&trace, quad().qpi(true)),
+        vec![(0x123456, DATA.to_vec())]
+    );
+}
+
+#[test]
+fn dual_reads() {
+    // 1-1-2 and 1-2-2.
+    let mut trace = cycles(1, &[0x3B]);
+    trace.extend(cycles(1, &ADDR));
+    trace.extend(vec![0; 8]);
+    trace.extend(cycles(2, &DATA));
+    assert_eq!(reads(&trace, quad()), vec![(0x123456, DATA.to_vec())]);
+
+    let mut trace = cycles(1, &[0xBB]);
+    trace.extend(cycles(2, &ADDR));
+    trace.extend(vec![0; 4]);
+    trace.extend(cycles(2, &DATA));
+    assert_eq!(reads(&trace, quad()), vec![(0x123456, DATA.to_vec())]);
+}
+
+#[test]
+fn single_read() {
+    // commands without a schedule are single SPI, the device answering on IO1.
+    let mut trace = cycles(1, &[0x03]);
+    trace.extend(cycles(1, &ADDR));
+    trace.extend(
+        DATA.iter()
+            .flat_map(|&b| cycles(1, &[b]).into_iter().map(|bit| bit << 1)),
+    );
+    assert_eq!(reads(&trace, quad()), vec![(0x123456, DATA.to_vec())]);
+}
+
+#[test]
+fn dummy_cycles_are_not_gaps() {
+    let mut trace = cycles(1, &[0xEB]);
+    trace.extend(cycles(4, &ADDR));
+    trace.extend(vec![0xF, 0xF, 0, 0, 0, 0]);
+    trace.extend(cycles(4, &DATA));
+    let samples = transaction(&trace);
+    let transactions: Vec<_> =
+        SpiTransactions::from_spi(quad().into_spi(samples.into_iter()).unwrap(), false)
+            .map(|res| res.unwrap().1)
+            .collect();
+    assert_eq!(transactions.len(), 1);
+    assert_eq!(transactions[0].mosi, [&[0xEB][..], &ADDR, &[0; 4]].concat());
+    assert_eq!(transactions[0].miso, [&[0; 4][..], &DATA].concat());
+    // the clock runs through the dummy cycles: every word follows the previous one by a period.
+    assert_eq!(transactions[0].gaps.len(), 7);
+    assert!(transactions[0]
+        .gaps
+        .iter()
+        .all(|gap| (gap - 3e-7).abs() < 1e-12));
+    let clock_hz = transactions[0].clock_hz.unwrap();
+    assert!((clock_hz - 1e7 / 3.).abs() < 1.);
+}
+
+#[test]
+fn quad_read_without_qspi() {
+    // the lanes of a quad read cannot be followed without --qspi, the command is not decoded.
+    for &command in &[0x3B, 0x6B, 0xBB, 0xEB] {
+        let mut trace = cycles(1, &[command]);
+        trace.extend(cycles(1, &ADDR));
+        let samples = transaction(&trace);
+        let res = Spif::from_spi(
+            SpiBuilder::new().into_spi(samples.into_iter()).unwrap(),
+            false,
+        )
+        .next();
+        match res {
+            Some(Err(Error::Protocol { ref msg, .. })) => {
+                assert_eq!(msg, &format!("Unsupported cmd {:x}-0", command))
+            }
+            _ => panic!(
+                "{:x} decoded as {:?}",
+                command,
+                res.map(|res| res.map(|r| r.1))
+            ),
+        }
+    }
+}
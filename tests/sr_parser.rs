@@ -0,0 +1,95 @@
+use logic_trace_parser::sr_parser::SrParser;
+use std::io::{Cursor, Write};
+use zip::write::{SimpleFileOptions, ZipWriter};
+use zip::CompressionMethod;
+
+const METADATA: &str = "[global]
+sigrok version=0.5.2
+
+[device 1]
+capturefile=logic-1
+total probes=4
+samplerate=2 MHz
+total analog=0
+probe1=CS
+probe2=MISO
+probe3=MOSI
+probe4=CLK
+unitsize=1
+";
+
+/// Dense samples: a counter on the 4 channels, each value held for `i % 3 + 1` samples.
+fn dense() -> Vec<u8> {
+    (0..40u8)
+        .flat_map(|i| std::iter::repeat_n(i & 0xF, usize::from(i % 3 + 1)))
+        .collect()
+}
+
+/// The samples changing the state, as the parser produces them at `rate`.
+fn changes(dense: &[u8], rate: f64, map: impl Fn(u8) -> u64) -> Vec<(f64, u64)> {
+    let mut changes: Vec<(f64, u64)> = Vec::new();
+    for (idx, &smp) in dense.iter().enumerate() {
+        let state = map(smp);
+        if changes.last().map(|&(_, last)| last) != Some(state) {
+            changes.push((idx as f64 / rate, state));
+        }
+    }
+    changes
+}
+
+/// Writes a session with the samples split in chunks of `chunk` bytes.
+fn session(samples: &[u8], chunk: usize, method: CompressionMethod) -> Vec<u8> {
+    let mut zip = ZipWriter::new(Cursor::new(Vec::new()));
+    let options = SimpleFileOptions::default().compression_method(method);
+    zip.start_file("version", options).unwrap();
+    zip.write_all(b"2").unwrap();
+    zip.start_file("metadata", options).unwrap();
+    zip.write_all(METADATA.as_bytes()).unwrap();
+    for (idx, data) in samples.chunks(chunk).enumerate() {
+        zip.start_file(format!("logic-1-{}", idx + 1), options)
+            .unwrap();
+        zip.write_all(data).unwrap();
+    }
+    zip.finish().unwrap().into_inner()
+}
+
+fn parse(parser: SrParser<&[u8]>) -> Vec<(f64, u64)> {
+    parser
+        .map(|smp| {
+            let smp = smp.unwrap();
+            (smp.timestamp(), smp.sample())
+        })
+        .collect()
+}
+
+#[test]
+fn round_trip() {
+    let samples = dense();
+    let expected = changes(&samples, 2e6, u64::from);
+    for &method in &[CompressionMethod::Stored, CompressionMethod::Deflated] {
+        for &chunk in &[7, 64, samples.len()] {
+            let session = session(&samples, chunk, method);
+            assert_eq!(
+                parse(SrParser::new(&session[..])),
+                expected,
+                "{:?}, chunks of {}",
+                method,
+                chunk
+            );
+        }
+    }
+}
+
+#[test]
+fn mapped_probes() {
+    let samples = dense();
+    let session = session(&samples, 16, CompressionMethod::Deflated);
+    let parser = SrParser::new(&session[..])
+        .map_probe("CLK", 0)
+        .map_probe("CS", 5);
+    // CLK is the 4th bit of a sample and CS the first.
+    let expected = changes(&samples, 2e6, |smp| {
+        u64::from((smp >> 3) & 1) | (u64::from(smp & 1) << 5)
+    });
+    assert_eq!(parse(parser), expected);
+}
@@ -0,0 +1,150 @@
+use logic_trace_parser::vcd_parser::{Undefined, VcdParser};
+use logic_trace_parser::Error;
+
+fn parse(parser: VcdParser<&[u8]>) -> Vec<Result<(f64, u64), Error>> {
+    parser
+        .map(|smp| smp.map(|smp| (smp.timestamp(), smp.sample())))
+        .collect()
+}
+
+fn samples(parser: VcdParser<&[u8]>) -> Vec<(f64, u64)> {
+    parse(parser)
+        .into_iter()
+        .map(|res| res.unwrap_or_else(|e| panic!("{}", e)))
+        .collect()
+}
+
+/// A capture with an 8-bit register and a clock declared as `var_decl`.
+fn vector(var_decl: &str) -> String {
+    format!(
+        "$timescale 1us $end
+$scope module top $end
+{}
+$var logic 1 \" clk $end
+$upscope $end
+$enddefinitions $end
+#0
+b10100101 !
+0\"
+#1
+1\"
+b1 !
+",
+        var_decl
+    )
+}
+
+#[test]
+fn reg_and_logic_vectors() {
+    for decl in &[
+        "$var reg 8 ! data [7:0] $end",
+        "$var reg 8 ! data[7:0] $end",
+        "$var logic 8 ! data [7:0] $end",
+        "$var wire 8 ! data $end",
+    ] {
+        let vcd = vector(decl);
+        let parser = VcdParser::new(vcd.as_bytes())
+            .map_signal("top.data", 0)
+            .map_signal("clk", 8);
+        assert_eq!(
+            samples(parser),
+            vec![(0., 0xA5), (0., 0xA5), (1e-6, 0x1A5), (1e-6, 0x101)],
+            "{}",
+            decl
+        );
+    }
+}
+
+#[test]
+fn vector_bits_lsb_first() {
+    // the least significant bit of a vector goes to the first channel.
+    let vcd = vector("$var reg 8 ! data [7:0] $end");
+    let parser = VcdParser::new(vcd.as_bytes()).map_signal("data", 4);
+    assert_eq!(samples(parser)[0], (0., 0xA50));
+
+    // single bits are selected by their declared index, whatever the direction of the range.
+    for &(decl, high, low) in &[
+        ("$var reg 8 ! data [7:0] $end", "data[7]", "data[6]"),
+        ("$var reg 8 ! data [0:7] $end", "data[0]", "data[1]"),
+        ("$var reg 8 ! data [8:15] $end", "data[8]", "data[9]"),
+    ] {
+        let vcd = vector(decl);
+        let parser = VcdParser::new(vcd.as_bytes())
+            .map_signal(high, 0)
+            .map_signal(low, 1);
+        assert_eq!(samples(parser)[0], (0., 0b01), "{}", decl);
+    }
+}
+
+#[test]
+fn channels_from_names() {
+    let vcd = "$timescale 1ns $end
+$scope module top $end
+$var wire 1 ! ch3 $end
+$var wire 4 # d4 [3:0] $end
+$upscope $end
+$enddefinitions $end
+#0
+1!
+b0110 #
+#5
+0!
+";
+    assert_eq!(
+        samples(VcdParser::new(vcd.as_bytes())),
+        vec![(0., 0x08), (0., 0x68), (5e-9, 0x60)]
+    );
+}
+
+fn undefined_capture() -> &'static str {
+    "$timescale 1ns $end
+$var reg 4 ! d0 [3:0] $end
+$enddefinitions $end
+#0
+b1111 !
+#1
+bx01z !
+#2
+bz !
+"
+}
+
+#[test]
+fn undefined_values() {
+    let vcd = undefined_capture();
+    let parser = VcdParser::new(vcd.as_bytes()).undefined(Undefined::Zero);
+    assert_eq!(
+        samples(parser),
+        vec![(0., 0b1111), (1e-9, 0b0010), (2e-9, 0b0000)]
+    );
+
+    let parser = VcdParser::new(vcd.as_bytes()).undefined(Undefined::Last);
+    assert_eq!(
+        samples(parser),
+        vec![(0., 0b1111), (1e-9, 0b1011), (2e-9, 0b1011)]
+    );
+
+    let res = parse(VcdParser::new(vcd.as_bytes()).undefined(Undefined::Warn));
+    assert_eq!(res.len(), 5);
+    assert_eq!(res[0].as_ref().ok(), Some(&(0., 0b1111)));
+    for (idx, ts) in &[(1, 1e-9), (3, 2e-9)] {
+        match res[*idx] {
+            Err(Error::UndefinedValue { ts: at, ref signal }) => {
+                assert_eq!((at, &signal[..]), (*ts, "d0"))
+            }
+            _ => panic!("expected an undefined value at {}", ts),
+        }
+    }
+    assert_eq!(res[2].as_ref().ok(), Some(&(1e-9, 0b1011)));
+    assert_eq!(res[4].as_ref().ok(), Some(&(2e-9, 0b1011)));
+}
+
+#[test]
+fn unknown_signal() {
+    let vcd = vector("$var reg 8 ! data [7:0] $end");
+    let res = parse(VcdParser::new(vcd.as_bytes()).map_signal("top.missing", 0));
+    match res[0] {
+        Err(Error::UnknownSignal(ref name)) => assert_eq!(name, "top.missing"),
+        _ => panic!("expected an unknown signal"),
+    }
+}